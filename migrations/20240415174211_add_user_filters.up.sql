@@ -0,0 +1,6 @@
+CREATE TABLE user_filter (
+    platform TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK(kind IN ('block', 'allow')),
+    PRIMARY KEY(platform, user_id)
+);
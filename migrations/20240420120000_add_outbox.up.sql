@@ -0,0 +1,7 @@
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY NOT NULL,
+    platform TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    message TEXT NOT NULL
+);
+CREATE INDEX outbox_platform ON outbox(platform, id);
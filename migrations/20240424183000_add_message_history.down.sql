@@ -0,0 +1,5 @@
+DROP TRIGGER message_history_fts_delete;
+DROP TRIGGER message_history_fts_insert;
+DROP TABLE message_history_fts;
+DROP TABLE message_delivery;
+DROP TABLE message_history;
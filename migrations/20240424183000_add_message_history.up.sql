@@ -0,0 +1,36 @@
+CREATE TABLE message_history (
+    id INTEGER PRIMARY KEY NOT NULL,
+    source_platform TEXT NOT NULL,
+    channel_id TEXT,
+    channel_name TEXT,
+    user_id TEXT,
+    user_name TEXT,
+    contents TEXT NOT NULL,
+    received_at INTEGER NOT NULL
+);
+CREATE INDEX message_history_channel ON message_history(source_platform, channel_id, id);
+CREATE INDEX message_history_received_at ON message_history(received_at);
+
+CREATE TABLE message_delivery (
+    message_id INTEGER NOT NULL REFERENCES message_history(id) ON DELETE CASCADE,
+    target_platform TEXT NOT NULL,
+    target_channel_id TEXT,
+    attempted_at INTEGER NOT NULL,
+    -- NULL when the message was delivered
+    error TEXT
+);
+CREATE INDEX message_delivery_message ON message_delivery(message_id);
+
+CREATE VIRTUAL TABLE message_history_fts USING fts5(
+    contents,
+    content = 'message_history',
+    content_rowid = 'id'
+);
+
+CREATE TRIGGER message_history_fts_insert AFTER INSERT ON message_history BEGIN
+    INSERT INTO message_history_fts(rowid, contents) VALUES (new.id, new.contents);
+END;
+
+CREATE TRIGGER message_history_fts_delete AFTER DELETE ON message_history BEGIN
+    INSERT INTO message_history_fts(message_history_fts, rowid, contents) VALUES ('delete', old.id, old.contents);
+END;
@@ -0,0 +1 @@
+ALTER TABLE message_history DROP COLUMN message_id;
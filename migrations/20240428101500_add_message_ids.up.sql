@@ -0,0 +1 @@
+ALTER TABLE message_history ADD COLUMN message_id TEXT;
@@ -0,0 +1,13 @@
+CREATE TABLE message_mapping (
+    source_platform TEXT NOT NULL,
+    source_channel_id TEXT,
+    source_message_id TEXT NOT NULL,
+    source_user_id TEXT,
+    target_platform TEXT NOT NULL,
+    target_channel_id TEXT,
+    target_message_id TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX message_mapping_source_message ON message_mapping(source_platform, source_message_id);
+CREATE INDEX message_mapping_source_user ON message_mapping(source_platform, source_user_id);
+CREATE INDEX message_mapping_created_at ON message_mapping(created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE user_link DROP COLUMN target_user_name;
+ALTER TABLE user_link DROP COLUMN source_user_name;
@@ -0,0 +1,5 @@
+ALTER TABLE user_link ADD COLUMN source_user_name TEXT;
+ALTER TABLE user_link ADD COLUMN target_user_name TEXT;
+-- Factorio identifies players by their name
+UPDATE user_link SET source_user_name = source_user_id WHERE source_platform = 'factorio';
+UPDATE user_link SET target_user_name = target_user_id WHERE target_platform = 'factorio';
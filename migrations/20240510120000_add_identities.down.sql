@@ -0,0 +1,20 @@
+CREATE TABLE user_link (
+    source_platform TEXT NOT NULL,
+    source_user_id TEXT NOT NULL,
+    target_platform TEXT NOT NULL,
+    target_user_id TEXT NOT NULL,
+    source_user_name TEXT,
+    target_user_name TEXT,
+    PRIMARY KEY(source_platform, source_user_id)
+);
+
+-- Each account can only be linked to one other account again
+INSERT OR IGNORE INTO user_link
+(source_platform, source_user_id, source_user_name, target_platform, target_user_id, target_user_name)
+SELECT source.platform, source.user_id, source.user_name, target.platform, target.user_id, target.user_name
+FROM identity_account source
+JOIN identity_account target ON target.identity_id = source.identity_id AND target.platform != source.platform
+ORDER BY source.platform, source.user_id, target.platform;
+
+DROP TABLE identity_account;
+DROP TABLE identity;
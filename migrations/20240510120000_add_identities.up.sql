@@ -0,0 +1,60 @@
+-- One person with accounts on several platforms, replaces the one-to-one user_link table
+CREATE TABLE identity (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- Used instead of the account's own name when set
+    display_name TEXT
+);
+
+CREATE TABLE identity_account (
+    platform TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    user_name TEXT,
+    identity_id INTEGER NOT NULL REFERENCES identity(id) ON DELETE CASCADE,
+    PRIMARY KEY(platform, user_id)
+);
+-- Messages are sent as the identity's only account on the target platform
+CREATE UNIQUE INDEX identity_account_identity_platform ON identity_account(identity_id, platform);
+
+-- Accounts connected through any chain of links become one identity
+CREATE TEMP TABLE migrated_account AS
+WITH RECURSIVE
+    account(platform, user_id, user_name) AS (
+        SELECT source_platform, source_user_id, MAX(source_user_name) FROM user_link
+        GROUP BY source_platform, source_user_id
+        UNION
+        SELECT target_platform, target_user_id, MAX(target_user_name) FROM user_link
+        GROUP BY target_platform, target_user_id
+    ),
+    edge(from_platform, from_user_id, to_platform, to_user_id) AS (
+        SELECT source_platform, source_user_id, target_platform, target_user_id FROM user_link
+        UNION
+        SELECT target_platform, target_user_id, source_platform, source_user_id FROM user_link
+    ),
+    reachable(platform, user_id, root) AS (
+        SELECT platform, user_id, platform || char(31) || user_id FROM account
+        UNION
+        SELECT edge.to_platform, edge.to_user_id, reachable.root
+        FROM reachable
+        JOIN edge ON edge.from_platform = reachable.platform AND edge.from_user_id = reachable.user_id
+    )
+SELECT account.platform, account.user_id, MAX(account.user_name) AS user_name, MIN(reachable.root) AS component
+FROM account
+JOIN reachable ON reachable.platform = account.platform AND reachable.user_id = account.user_id
+GROUP BY account.platform, account.user_id;
+
+CREATE TEMP TABLE migrated_identity AS
+SELECT component, ROW_NUMBER() OVER (ORDER BY component) AS id
+FROM (SELECT DISTINCT component FROM migrated_account);
+
+INSERT INTO identity (id) SELECT id FROM migrated_identity;
+
+-- If a person had several accounts on one platform, only the first one is kept
+INSERT OR IGNORE INTO identity_account (platform, user_id, user_name, identity_id)
+SELECT migrated_account.platform, migrated_account.user_id, migrated_account.user_name, migrated_identity.id
+FROM migrated_account
+JOIN migrated_identity ON migrated_identity.component = migrated_account.component
+ORDER BY migrated_account.platform, migrated_account.user_id;
+
+DROP TABLE migrated_account;
+DROP TABLE migrated_identity;
+DROP TABLE user_link;
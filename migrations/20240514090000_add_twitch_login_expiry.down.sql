@@ -0,0 +1,2 @@
+ALTER TABLE twitch_login DROP COLUMN needs_reauth;
+ALTER TABLE twitch_login DROP COLUMN expires_at;
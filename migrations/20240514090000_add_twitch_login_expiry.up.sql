@@ -0,0 +1,4 @@
+-- Unknown for logins saved before tokens were refreshed in the background, those are checked on the next refresh
+ALTER TABLE twitch_login ADD COLUMN expires_at INTEGER;
+-- Set when Twitch rejected the refresh token, the user has to log in again
+ALTER TABLE twitch_login ADD COLUMN needs_reauth BOOLEAN NOT NULL DEFAULT FALSE;
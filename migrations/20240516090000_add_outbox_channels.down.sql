@@ -0,0 +1,4 @@
+DROP INDEX outbox_channel;
+CREATE INDEX outbox_platform ON outbox(platform, id);
+ALTER TABLE outbox DROP COLUMN attempts;
+ALTER TABLE outbox DROP COLUMN channel_id;
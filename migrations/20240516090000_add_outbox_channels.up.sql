@@ -0,0 +1,6 @@
+-- Messages are kept in order per channel, so one failing channel does not hold back the others
+ALTER TABLE outbox ADD COLUMN channel_id TEXT;
+ALTER TABLE outbox ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+UPDATE outbox SET channel_id = json_extract(message, '$.target_channel_id');
+DROP INDEX outbox_platform;
+CREATE INDEX outbox_channel ON outbox(platform, channel_id, id);
@@ -0,0 +1,282 @@
+use crate::{
+    builder::{PlatformStatus, PlatformStatuses},
+    router::SharedRouter,
+    user_links::{Account, Identity, UserLinks},
+    ChannelIdentifier, IncomingMessage, MessageBody, OutgoingEvent, UserIdentifier,
+};
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, patch, post},
+    Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// Authenticated JSON API for managing the bridge, served under `/admin`
+#[derive(Clone)]
+pub struct AdminApi {
+    pub message_router: SharedRouter,
+    pub message_senders: HashMap<&'static str, mpsc::Sender<OutgoingEvent>>,
+    pub platform_statuses: PlatformStatuses,
+    pub user_links: UserLinks,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+impl AdminApi {
+    pub fn routes(self, token: String) -> axum::Router {
+        axum::Router::new()
+            .route("/bridges", get(list_bridges))
+            .route("/platforms", get(list_platforms))
+            .route("/messages", post(send_message))
+            .route("/identities", get(list_identities))
+            .route("/identities/:id", patch(update_identity))
+            .route("/links", post(create_link))
+            .route("/links/:platform/:user_id", delete(delete_link))
+            .layer(middleware::from_fn_with_state(token, require_token))
+            .with_state(self)
+    }
+}
+
+/// Requests have to carry the configured token as `Authorization: Bearer <token>`
+pub async fn require_token(
+    State(token): State<String>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if has_bearer_token(request.headers(), &token) {
+        Ok(next.run(request).await)
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+pub fn has_bearer_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given_token| constant_time_eq(given_token.as_bytes(), token.as_bytes()))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn internal_error(err: anyhow::Error) -> (StatusCode, String) {
+    error!("Admin API error: {err:#}");
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+#[derive(Serialize)]
+struct BridgeEntry {
+    channels: Vec<BridgeChannelEntry>,
+    bidirectional: bool,
+}
+
+#[derive(Serialize)]
+struct BridgeChannelEntry {
+    channel: String,
+    paused: bool,
+}
+
+#[derive(Serialize)]
+struct MutedUserEntry {
+    /// Messages of the user are only muted in this channel
+    channel: String,
+    user_id: String,
+    user_name: String,
+}
+
+#[derive(Serialize)]
+struct BridgesResponse {
+    bridges: Vec<BridgeEntry>,
+    /// Users muted with the bridge command
+    muted_users: Vec<MutedUserEntry>,
+}
+
+async fn list_bridges(State(api): State<AdminApi>) -> Json<BridgesResponse> {
+    let router = api.message_router.read().unwrap();
+
+    let bridges = router
+        .bridges
+        .iter()
+        .map(|bridge| BridgeEntry {
+            channels: bridge
+                .channels
+                .iter()
+                .map(|channel| BridgeChannelEntry {
+                    channel: channel.to_string(),
+                    paused: router.state.paused_channels.contains(channel),
+                })
+                .collect(),
+            bidirectional: bridge.bidirectional,
+        })
+        .collect();
+    let muted_users = router
+        .state
+        .muted_users
+        .iter()
+        .map(|((channel, user_id), user_name)| MutedUserEntry {
+            channel: channel.to_string(),
+            user_id: user_id.clone(),
+            user_name: user_name.clone(),
+        })
+        .collect();
+
+    Json(BridgesResponse {
+        bridges,
+        muted_users,
+    })
+}
+
+async fn list_platforms(
+    State(api): State<AdminApi>,
+) -> Json<HashMap<&'static str, PlatformStatus>> {
+    Json(api.platform_statuses.read().unwrap().clone())
+}
+
+#[derive(Deserialize)]
+struct SendMessageRequest {
+    /// e.g. `twitch:12345678`
+    channel: String,
+    contents: String,
+}
+
+/// Sends a message to a single channel, it is not mirrored to the rest of the bridge
+async fn send_message(
+    State(api): State<AdminApi>,
+    Json(request): Json<SendMessageRequest>,
+) -> ApiResult<StatusCode> {
+    let channel: ChannelIdentifier = request.channel.parse().unwrap();
+    let Some(sender) = api.message_senders.get(channel.platform.as_str()) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Platform {} is not configured", channel.platform),
+        ));
+    };
+
+    let timestamp = Utc::now();
+    let incoming_msg = IncomingMessage {
+        id: format!("admin-{}", timestamp.timestamp_micros()),
+        timestamp,
+        channel_id: channel.value.clone(),
+        channel_name: None,
+        user_id: None,
+        user_name: None,
+        body: MessageBody::from_text(&request.contents),
+        user_color: None,
+        provenance: None,
+        event: None,
+        user_is_admin: false,
+    };
+    let outgoing_msg = api.message_router.read().unwrap().direct_message(
+        &channel.platform,
+        &incoming_msg,
+        request.contents,
+    );
+
+    info!("Sending message to {channel} through the admin API");
+    sender
+        .send(OutgoingEvent::Message(Box::new(outgoing_msg)))
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The bridge is shutting down".to_owned(),
+            )
+        })?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn list_identities(State(api): State<AdminApi>) -> ApiResult<Json<Vec<Identity>>> {
+    api.user_links
+        .list()
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+#[derive(Deserialize)]
+struct UpdateIdentityRequest {
+    display_name: Option<String>,
+}
+
+async fn update_identity(
+    State(api): State<AdminApi>,
+    Path(identity_id): Path<i64>,
+    Json(request): Json<UpdateIdentityRequest>,
+) -> ApiResult<StatusCode> {
+    let updated = api
+        .user_links
+        .set_display_name(identity_id, request.display_name.as_deref())
+        .await
+        .map_err(internal_error)?;
+
+    if updated {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "Identity does not exist".to_owned()))
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateLinkRequest {
+    account: Account,
+    linked_account: Account,
+}
+
+#[derive(Serialize)]
+struct CreateLinkResponse {
+    identity_id: i64,
+}
+
+async fn create_link(
+    State(api): State<AdminApi>,
+    Json(request): Json<CreateLinkRequest>,
+) -> ApiResult<(StatusCode, Json<CreateLinkResponse>)> {
+    if request.account.platform == request.linked_account.platform {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Linked accounts have to be on different platforms".to_owned(),
+        ));
+    }
+
+    let identity_id = api
+        .user_links
+        .link(&request.account, &request.linked_account)
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "Linked {}:{} to {}:{} through the admin API",
+        request.account.platform,
+        request.account.user_id,
+        request.linked_account.platform,
+        request.linked_account.user_id
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateLinkResponse { identity_id }),
+    ))
+}
+
+async fn delete_link(
+    State(api): State<AdminApi>,
+    Path((platform, user_id)): Path<(String, String)>,
+) -> ApiResult<StatusCode> {
+    let user = UserIdentifier { platform, user_id };
+    let removed = api.user_links.unlink(&user).await.map_err(internal_error)?;
+
+    if removed {
+        info!("Removed link of {user:?} through the admin API");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "User has no link".to_owned()))
+    }
+}
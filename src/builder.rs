@@ -1,31 +1,82 @@
 use crate::{
-    platforms::ChatPlatform, router::MessageRouter, DbPool, IncomingMessage, OutgoingMessage,
+    commands::CommandRegistry, health::HealthChecker, history::MessageHistory,
+    message_body::BodyRenderer, message_mapping::MessageMappings, metrics, outbox::Outbox,
+    platforms::ChatPlatform, router::SharedRouter, template::MessageTemplates, DbPool,
+    IncomingEvent, OutgoingDeletion, OutgoingEvent, OutgoingMessage,
 };
-use anyhow::Context;
-use std::collections::HashMap;
-use tokio::{sync::mpsc, task::JoinHandle};
-use tracing::{debug, info};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
+
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(5 * 60);
+/// A platform that ran for this long before failing starts over with the initial restart delay
+const RESTART_DELAY_RESET_AFTER: Duration = Duration::from_secs(10 * 60);
 
 type PlatformHandle = JoinHandle<(&'static str, anyhow::Result<()>)>;
 
+/// Current state of every configured platform, by name
+pub type PlatformStatuses = Arc<RwLock<HashMap<&'static str, PlatformStatus>>>;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PlatformStatus {
+    Running {
+        since: DateTime<Utc>,
+    },
+    /// The platform failed and is waiting to be restarted
+    Restarting {
+        error: String,
+        attempt: u32,
+    },
+    Stopped,
+}
+
 pub struct PlatformsBuilder<'a> {
-    message_router: &'a MessageRouter,
+    message_router: &'a SharedRouter,
     global_config: &'a crate::Config,
     db: &'a DbPool,
+    shutdown_rx: watch::Receiver<bool>,
+    outbox: Option<Outbox>,
+    delivery: Delivery,
 
-    pub message_senders: HashMap<&'static str, mpsc::Sender<OutgoingMessage>>,
+    pub message_senders: HashMap<&'static str, mpsc::Sender<OutgoingEvent>>,
     pub api_router: axum::Router,
-    pub incoming_messages_tx: mpsc::Sender<(&'static str, IncomingMessage)>,
-    pub incoming_messages_rx: mpsc::Receiver<(&'static str, IncomingMessage)>,
+    pub incoming_messages_tx: mpsc::Sender<(&'static str, IncomingEvent)>,
+    pub incoming_messages_rx: mpsc::Receiver<(&'static str, IncomingEvent)>,
     pub platform_handles: Vec<PlatformHandle>,
+    pub platform_statuses: PlatformStatuses,
+    /// Accounts the bridge uses on the platforms that have one, by platform
+    pub bot_user_ids: HashMap<&'static str, String>,
+    /// Finish after the platform's queued messages were sent and it was shut down
+    pub sender_handles: Vec<JoinHandle<()>>,
     pub zws_support: HashMap<&'static str, bool>,
+    pub deletion_support: HashMap<&'static str, bool>,
+    pub body_renderers: HashMap<&'static str, BodyRenderer>,
+    pub commands: CommandRegistry,
+    pub health_checkers: HashMap<&'static str, HealthChecker>,
+    pub default_templates: HashMap<&'static str, MessageTemplates>,
+    /// Used to notify platforms about changes of their mirrored channels
+    pub channel_updaters: HashMap<&'static str, watch::Sender<Vec<String>>>,
 }
 
 impl<'a> PlatformsBuilder<'a> {
     pub fn new(
         global_config: &'a crate::Config,
-        message_router: &'a MessageRouter,
+        message_router: &'a SharedRouter,
         db: &'a DbPool,
+        shutdown_rx: watch::Receiver<bool>,
+        delivery: Delivery,
     ) -> Self {
         let (incoming_messages_tx, incoming_messages_rx) = mpsc::channel(1000);
 
@@ -33,27 +84,47 @@ impl<'a> PlatformsBuilder<'a> {
             message_router,
             global_config,
             db,
+            shutdown_rx,
+            outbox: global_config
+                .outbox
+                .as_ref()
+                .map(|outbox_config| Outbox::new(db, message_router, outbox_config)),
+            delivery,
             message_senders: HashMap::new(),
             api_router: axum::Router::new(),
             incoming_messages_tx,
             incoming_messages_rx,
             platform_handles: Vec::new(),
+            platform_statuses: PlatformStatuses::default(),
+            bot_user_ids: HashMap::new(),
+            sender_handles: Vec::new(),
             zws_support: HashMap::new(),
+            deletion_support: HashMap::new(),
+            body_renderers: HashMap::new(),
+            commands: CommandRegistry::default(),
+            health_checkers: HashMap::new(),
+            default_templates: HashMap::new(),
+            channel_updaters: HashMap::new(),
         }
     }
 
     pub async fn init_platform<T: ChatPlatform>(&mut self) -> anyhow::Result<()> {
         self.zws_support.insert(T::NAME, T::supports_zws());
+        self.deletion_support
+            .insert(T::NAME, T::supports_deletion());
+        self.body_renderers.insert(T::NAME, T::render_body);
+        let default_templates =
+            MessageTemplates::parse(T::default_template(), T::default_system_template())
+                .with_context(|| format!("Invalid default template for platform {}", T::NAME))?;
+        self.default_templates.insert(T::NAME, default_templates);
 
         match self.global_config.platforms.get(T::NAME) {
             Some(raw_config) => {
                 let channels = self
                     .message_router
-                    .channel_links
-                    .keys()
-                    .filter(|channel| channel.platform == T::NAME)
-                    .filter_map(|channel| channel.value.clone())
-                    .collect::<Vec<String>>();
+                    .read()
+                    .unwrap()
+                    .platform_channels(T::NAME);
 
                 info!("Initializing platform {}...", T::NAME);
                 let platform_config: T::Config = raw_config
@@ -63,9 +134,17 @@ impl<'a> PlatformsBuilder<'a> {
 
                 let (platform_incoming_tx, mut platform_incoming_rx) = mpsc::channel(100);
 
-                let mut platform = T::new(platform_config, self.global_config, channels, self.db)
-                    .await
-                    .with_context(|| format!("Could initialize platform {}", T::NAME))?;
+                let mut platform = T::new(
+                    platform_config,
+                    self.global_config,
+                    channels.clone(),
+                    self.db,
+                )
+                .await
+                .with_context(|| format!("Could initialize platform {}", T::NAME))?;
+                if let Some(bot_user_id) = platform.bot_user_id() {
+                    self.bot_user_ids.insert(T::NAME, bot_user_id);
+                }
 
                 let platform_router = platform
                     .api_routes()
@@ -79,19 +158,68 @@ impl<'a> PlatformsBuilder<'a> {
                 let incoming_messages_tx = self.incoming_messages_tx.clone();
                 tokio::spawn(async move {
                     while let Some(message) = platform_incoming_rx.recv().await {
-                        incoming_messages_tx.send((T::NAME, message)).await.unwrap();
+                        // The router stops receiving when shutting down
+                        if incoming_messages_tx.send((T::NAME, message)).await.is_err() {
+                            break;
+                        }
                     }
                 });
 
                 let (platform_outgoing_tx, platform_outgoing_rx) = mpsc::channel(100);
                 self.message_senders.insert(T::NAME, platform_outgoing_tx);
 
-                let handle = tokio::spawn(async {
-                    let handle = platform
-                        .run(platform_incoming_tx, platform_outgoing_rx)
-                        .await;
-                    (T::NAME, handle)
+                let platform = Arc::new(platform);
+
+                for spec in T::commands() {
+                    let command_platform = platform.clone();
+                    self.commands.register(
+                        T::NAME,
+                        spec,
+                        Arc::new(move |request| {
+                            let platform = command_platform.clone();
+                            Box::pin(async move { platform.run_command(request).await })
+                        }),
+                    )?;
+                }
+
+                let checked_platform = platform.clone();
+                self.health_checkers.insert(
+                    T::NAME,
+                    Arc::new(move || {
+                        let platform = checked_platform.clone();
+                        Box::pin(async move { platform.health_checks().await })
+                    }),
+                );
+
+                let (channels_tx, mut channels_rx) = watch::channel(channels);
+                self.channel_updaters.insert(T::NAME, channels_tx);
+
+                let updated_platform = platform.clone();
+                tokio::spawn(async move {
+                    while channels_rx.changed().await.is_ok() {
+                        let channels = channels_rx.borrow_and_update().clone();
+                        info!("Updating channels of platform {}", T::NAME);
+                        if let Err(err) = updated_platform.update_channels(channels).await {
+                            error!("Could not update channels of platform {}: {err:#}", T::NAME);
+                        }
+                    }
                 });
+
+                let sender_handle = tokio::spawn(send_loop(
+                    platform.clone(),
+                    platform_outgoing_rx,
+                    self.outbox.clone(),
+                    self.delivery.clone(),
+                    self.global_config.general.offline_notice.clone(),
+                ));
+                self.sender_handles.push(sender_handle);
+
+                let handle = tokio::spawn(supervise(
+                    platform,
+                    platform_incoming_tx,
+                    self.platform_statuses.clone(),
+                    self.shutdown_rx.clone(),
+                ));
                 self.platform_handles.push(handle);
 
                 Ok(())
@@ -103,3 +231,155 @@ impl<'a> PlatformsBuilder<'a> {
         }
     }
 }
+
+/// Sends the platform's messages until its queue is closed on shutdown
+async fn send_loop<T: ChatPlatform>(
+    platform: Arc<T>,
+    mut outgoing_rx: mpsc::Receiver<OutgoingEvent>,
+    outbox: Option<Outbox>,
+    delivery: Delivery,
+    offline_notice: Option<String>,
+) {
+    match outbox {
+        Some(outbox) => {
+            outbox
+                .send_all(platform.as_ref(), &delivery, &mut outgoing_rx)
+                .await;
+        }
+        None => {
+            while let Some(outgoing_event) = outgoing_rx.recv().await {
+                match outgoing_event {
+                    OutgoingEvent::Message(outgoing_msg) => {
+                        if let Err(err) = delivery.send(platform.as_ref(), *outgoing_msg).await {
+                            error!("Could not send message to platform {}: {err:#}", T::NAME);
+                        }
+                    }
+                    OutgoingEvent::Deletion(deletion) => {
+                        delivery.delete(platform.as_ref(), deletion).await;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Err(err) = platform.shutdown(offline_notice.as_deref()).await {
+        error!("Could not shut down platform {}: {err:#}", T::NAME);
+    }
+}
+
+/// Sends messages to platforms, keeping track of the results
+#[derive(Clone)]
+pub struct Delivery {
+    pub history: MessageHistory,
+    pub mappings: MessageMappings,
+}
+
+impl Delivery {
+    pub async fn send<T: ChatPlatform>(
+        &self,
+        platform: &T,
+        outgoing_msg: OutgoingMessage,
+    ) -> anyhow::Result<()> {
+        let result = platform.send_msg(outgoing_msg.clone()).await;
+        match &result {
+            Ok(_) => {
+                metrics::MESSAGES_DELIVERED.inc(&[T::NAME]);
+                let latency = Utc::now() - outgoing_msg.source_msg.timestamp;
+                metrics::DELIVERY_LATENCY.observe(
+                    &[T::NAME],
+                    latency.num_milliseconds().max(0) as f64 / 1000.0,
+                );
+            }
+            Err(_) => metrics::SEND_ERRORS.inc(&[T::NAME]),
+        }
+        self.history
+            .record_delivery(T::NAME, &outgoing_msg, result.as_ref().err())
+            .await;
+
+        if let Some(target_message_id) = result? {
+            if let Err(err) = self
+                .mappings
+                .record(T::NAME, &outgoing_msg, &target_message_id)
+                .await
+            {
+                error!("{err:#}");
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete<T: ChatPlatform>(&self, platform: &T, deletion: OutgoingDeletion) {
+        debug!("Deleting message {deletion:?} on platform {}", T::NAME);
+        if let Err(err) = platform.delete_msg(deletion).await {
+            error!("Could not delete message on platform {}: {err:#}", T::NAME);
+        }
+    }
+}
+
+/// Runs the platform until shutdown, restarting it with an exponential backoff when it fails
+async fn supervise<T: ChatPlatform>(
+    platform: Arc<T>,
+    incoming_message_tx: mpsc::Sender<IncomingEvent>,
+    statuses: PlatformStatuses,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> (&'static str, anyhow::Result<()>) {
+    let mut restart_delay = INITIAL_RESTART_DELAY;
+    let mut attempt = 0;
+    let set_status = |status| {
+        statuses.write().unwrap().insert(T::NAME, status);
+    };
+
+    loop {
+        let started_at = Instant::now();
+        set_status(PlatformStatus::Running { since: Utc::now() });
+
+        // Running in a separate task so a panic can be handled like any other failure
+        let running_platform = platform.clone();
+        let incoming_message_tx = incoming_message_tx.clone();
+        let mut run_handle =
+            tokio::spawn(async move { running_platform.run(incoming_message_tx).await });
+
+        let result = tokio::select! {
+            result = &mut run_handle => result,
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown) => {
+                run_handle.abort();
+                info!("Platform {} stopped", T::NAME);
+                set_status(PlatformStatus::Stopped);
+                return (T::NAME, Ok(()));
+            }
+        };
+
+        let err = match result {
+            Ok(Ok(())) => {
+                info!("Platform {} stopped", T::NAME);
+                set_status(PlatformStatus::Stopped);
+                return (T::NAME, Ok(()));
+            }
+            Ok(Err(err)) => err,
+            Err(err) => anyhow!("Platform panicked: {err}"),
+        };
+
+        if started_at.elapsed() >= RESTART_DELAY_RESET_AFTER {
+            restart_delay = INITIAL_RESTART_DELAY;
+            attempt = 0;
+        }
+        attempt += 1;
+
+        warn!(
+            "Platform {} failed: {err:#}, restarting in {restart_delay:?} (attempt {attempt})",
+            T::NAME
+        );
+        set_status(PlatformStatus::Restarting {
+            error: format!("{err:#}"),
+            attempt,
+        });
+        tokio::select! {
+            _ = tokio::time::sleep(restart_delay) => (),
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown) => {
+                set_status(PlatformStatus::Stopped);
+                return (T::NAME, Ok(()));
+            }
+        }
+        restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    router::SharedRouter,
+    user_links::{Account, UserLinks, LINK_CODE_LIFETIME},
+    ChannelIdentifier, UserIdentifier,
+};
+use anyhow::bail;
+use futures::future::BoxFuture;
+use std::{collections::HashMap, sync::Arc};
+
+/// Name of the built-in command listing the available ones
+pub const HELP_COMMAND: &str = "help";
+/// Name of the built-in command for moderators controlling the bridge
+pub const BRIDGE_COMMAND: &str = "bridge";
+/// Name of the built-in command for linking accounts on different platforms
+pub const LINK_COMMAND: &str = "link";
+/// Name of the built-in command removing the user's account links
+pub const UNLINK_COMMAND: &str = "unlink";
+
+const RESERVED_COMMANDS: [&str; 4] = [HELP_COMMAND, BRIDGE_COMMAND, LINK_COMMAND, UNLINK_COMMAND];
+
+/// A command provided by a platform
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// A command sent in a bridged channel
+#[derive(Debug, Clone)]
+pub struct CommandRequest {
+    pub name: String,
+    /// Everything after the command name
+    pub args: String,
+    pub channel: ChannelIdentifier,
+    pub user_id: Option<String>,
+    pub user_name: Option<String>,
+    /// Whether the user is a moderator or admin in the channel the command was sent in
+    pub user_is_admin: bool,
+    /// (user id, name) of the users mentioned in the arguments
+    pub mentioned_users: Vec<(String, String)>,
+}
+
+/// Runs the command on the platform providing it, returning the reply
+pub type CommandHandler =
+    Arc<dyn Fn(CommandRequest) -> BoxFuture<'static, anyhow::Result<String>> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RegisteredCommand {
+    pub platform: &'static str,
+    pub description: &'static str,
+    pub handler: CommandHandler,
+}
+
+/// Commands of all configured platforms, by name
+#[derive(Clone, Default)]
+pub struct CommandRegistry {
+    commands: HashMap<&'static str, RegisteredCommand>,
+}
+
+impl CommandRegistry {
+    pub fn register(
+        &mut self,
+        platform: &'static str,
+        spec: &CommandSpec,
+        handler: CommandHandler,
+    ) -> anyhow::Result<()> {
+        if RESERVED_COMMANDS.contains(&spec.name) {
+            bail!("Command '{}' is reserved", spec.name);
+        }
+        if let Some(existing) = self.commands.get(spec.name) {
+            bail!(
+                "Command '{}' is already provided by platform {}",
+                spec.name,
+                existing.platform
+            );
+        }
+
+        self.commands.insert(
+            spec.name,
+            RegisteredCommand {
+                platform,
+                description: spec.description,
+                handler,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RegisteredCommand> {
+        self.commands.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &RegisteredCommand)> {
+        self.commands.iter().map(|(name, command)| (*name, command))
+    }
+}
+
+/// A command that was invoked in a channel where it is available
+pub enum Command {
+    /// Lists the given commands with their descriptions
+    Help {
+        prefix: String,
+        available: Vec<(&'static str, &'static str)>,
+    },
+    /// Changes the routing, e.g. pausing a channel
+    Bridge { request: CommandRequest },
+    /// `link <platform> <name>` requests a code, `link <code>` posted by that account confirms it
+    Link {
+        prefix: String,
+        request: CommandRequest,
+        user_links: UserLinks,
+    },
+    Unlink {
+        request: CommandRequest,
+        user_links: UserLinks,
+    },
+    Platform {
+        handler: CommandHandler,
+        request: CommandRequest,
+    },
+}
+
+impl Command {
+    pub async fn execute(self, message_router: &SharedRouter) -> anyhow::Result<String> {
+        match self {
+            Command::Help {
+                prefix,
+                mut available,
+            } => {
+                available.sort();
+                let mut reply = format!("Commands: {prefix}{HELP_COMMAND}");
+                for (name, description) in available {
+                    reply.push_str(&format!(", {prefix}{name} ({description})"));
+                }
+                Ok(reply)
+            }
+            Command::Bridge { request } => {
+                if !request.user_is_admin {
+                    return Ok("Only moderators can manage the bridge".to_owned());
+                }
+                Ok(message_router.write().unwrap().run_bridge_command(&request))
+            }
+            Command::Link {
+                prefix,
+                request,
+                user_links,
+            } => link(&prefix, request, &user_links, message_router).await,
+            Command::Unlink {
+                request,
+                user_links,
+            } => {
+                let Some(user) = request.user() else {
+                    return Ok("Accounts can only be linked by users".to_owned());
+                };
+                if user_links.unlink(&user).await? {
+                    Ok("Your account is no longer linked".to_owned())
+                } else {
+                    Ok("You have no linked accounts".to_owned())
+                }
+            }
+            Command::Platform { handler, request } => handler(request).await,
+        }
+    }
+}
+
+impl CommandRequest {
+    fn user(&self) -> Option<UserIdentifier> {
+        Some(UserIdentifier {
+            platform: self.channel.platform.clone(),
+            user_id: self.user_id.clone()?,
+        })
+    }
+}
+
+async fn link(
+    prefix: &str,
+    request: CommandRequest,
+    user_links: &UserLinks,
+    message_router: &SharedRouter,
+) -> anyhow::Result<String> {
+    let Some(user) = request.user() else {
+        return Ok("Accounts can only be linked by users".to_owned());
+    };
+    let usage = format!("Usage: {prefix}{LINK_COMMAND} <platform> <your name there>");
+
+    match request.args.split_once(' ') {
+        Some((target_platform, target_user_name)) => {
+            let target_platform = target_platform.to_lowercase();
+            let target_user_name = target_user_name.trim();
+            if target_platform == user.platform {
+                return Ok("The other account has to be on a different platform".to_owned());
+            }
+            let is_bridged = message_router
+                .read()
+                .unwrap()
+                .bridges
+                .iter()
+                .flat_map(|bridge| &bridge.channels)
+                .any(|channel| channel.platform == target_platform);
+            if !is_bridged {
+                return Ok(format!("Platform {target_platform} is not bridged"));
+            }
+
+            let account = Account {
+                platform: user.platform,
+                user_id: user.user_id,
+                user_name: request.user_name,
+            };
+            let code = user_links.request_link(account, target_platform.clone(), target_user_name);
+            Ok(format!(
+                "Post \"{prefix}{LINK_COMMAND} {code}\" as {target_user_name} on {target_platform} within {} minutes",
+                LINK_CODE_LIFETIME.as_secs() / 60
+            ))
+        }
+        None if !request.args.is_empty() && request.args.chars().all(|c| c.is_ascii_digit()) => {
+            let code = request.args.as_str();
+            let pending = request
+                .user_name
+                .as_deref()
+                .and_then(|user_name| user_links.redeem_code(code, &user.platform, user_name));
+            let Some(pending) = pending else {
+                return Ok(
+                    "The code is not valid for this account or expired, request a new one from your other account"
+                        .to_owned(),
+                );
+            };
+
+            let account = Account {
+                platform: user.platform,
+                user_id: user.user_id,
+                user_name: request.user_name,
+            };
+            user_links.link(&pending.account, &account).await?;
+
+            let linked = pending.account;
+            Ok(format!(
+                "Linked your account to {} on {}",
+                linked.user_name.as_deref().unwrap_or(&linked.user_id),
+                linked.platform
+            ))
+        }
+        None => Ok(usage),
+    }
+}
@@ -1,3 +1,4 @@
+use crate::events::EventKind;
 use serde::Deserialize;
 use std::collections::HashMap;
 use toml::Table;
@@ -10,6 +11,10 @@ pub struct Config {
     pub bridge: Vec<Bridge>,
     #[serde(default)]
     pub message: Message,
+    /// Undeliverable messages are only kept for retrying when this is set
+    pub outbox: Option<Outbox>,
+    #[serde(default)]
+    pub history: History,
 }
 
 #[derive(Deserialize, Clone)]
@@ -19,26 +24,106 @@ pub struct General {
     #[serde(default = "default_listen_address")]
     pub listen_address: String,
     pub base_url: String,
+    /// How long to wait for queued messages to be delivered when shutting down, in seconds
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Posted to every bridged channel when the bridge shuts down
+    pub offline_notice: Option<String>,
+    /// Bearer token for the `/admin` API, which is disabled when not set
+    pub admin_token: Option<String>,
+    /// Platforms that have to be healthy for `/readyz` to succeed, all configured platforms by default
+    pub required_platforms: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Bridge {
-    pub channels: [String; 2],
+    pub channels: Vec<BridgeChannel>,
+    /// When disabled, only messages from the first channel are mirrored to the others
     pub bidirectional: Option<bool>,
     pub insert_zws_into_names: Option<bool>,
     #[serde(default)]
     pub exclude_filters: Vec<String>,
+    /// When not empty, only messages matching one of these are mirrored
+    #[serde(default)]
+    pub include_filters: Vec<String>,
+    /// Only mirror messages from users on the allow list
+    pub allowed_users_only: Option<bool>,
     #[serde(default)]
     pub filter_mode: FilterMode,
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRule>,
+    pub template: Option<String>,
+    pub system_template: Option<String>,
+    /// Messages received longer ago than this many seconds (e.g. after being delayed in the outbox) are not mirrored
+    pub max_message_age: Option<u64>,
+    /// Whether deleting a message also removes its copies, enabled by default
+    pub propagate_deletions: Option<bool>,
+    /// Which kinds of events (joins, raids, ...) are mirrored, all of them by default
+    pub events: Option<Vec<EventKind>>,
+    /// Event kind -> template describing the event
+    #[serde(default)]
+    pub event_templates: HashMap<EventKind, String>,
+    /// Names of the commands that can be used in the bridge's channels, all of them by default
+    pub commands: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum BridgeChannel {
+    Plain(String),
+    Detailed(Box<BridgeChannelConfig>),
+}
+
+/// Per-channel overrides, applied to messages delivered to this channel
+#[derive(Deserialize, Clone)]
+pub struct BridgeChannelConfig {
+    pub channel: String,
+    pub insert_zws_into_names: Option<bool>,
+    pub exclude_filters: Option<Vec<String>>,
+    pub include_filters: Option<Vec<String>>,
+    pub allowed_users_only: Option<bool>,
+    pub filter_mode: Option<FilterMode>,
+    pub rewrite_rules: Option<Vec<RewriteRule>>,
+    pub template: Option<String>,
+    pub system_template: Option<String>,
+    pub max_message_age: Option<u64>,
+    pub propagate_deletions: Option<bool>,
+    pub events: Option<Vec<EventKind>>,
+    pub event_templates: Option<HashMap<EventKind, String>>,
 }
 
-#[derive(Clone, Copy, Default, Deserialize, Debug)]
+impl BridgeChannel {
+    pub fn channel(&self) -> &str {
+        match self {
+            BridgeChannel::Plain(channel) => channel,
+            BridgeChannel::Detailed(config) => &config.channel,
+        }
+    }
+
+    pub fn overrides(&self) -> Option<&BridgeChannelConfig> {
+        match self {
+            BridgeChannel::Plain(_) => None,
+            BridgeChannel::Detailed(config) => Some(config.as_ref()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq, Eq)]
 pub enum FilterMode {
     #[default]
     FinalMessage,
     SourceMessage,
 }
 
+/// Rules are applied in order, the replacement can reference capture groups with `$1` or `$name`
+#[derive(Deserialize, Clone)]
+pub struct RewriteRule {
+    pub pattern: String,
+    pub replacement: String,
+    /// Which part of the message the rule applies to, defaults to the bridge's filter mode
+    pub mode: Option<FilterMode>,
+}
+
 fn default_log_level() -> String {
     "info".to_owned()
 }
@@ -47,7 +132,102 @@ fn default_listen_address() -> String {
     "0.0.0.0:8000".to_owned()
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Message {
+    #[serde(default)]
     pub platform_aliases: HashMap<String, String>,
+    /// How many times a message can be relayed before it stops being mirrored further
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u32,
+    /// Default template for user messages, platforms have their own default if not set
+    pub template: Option<String>,
+    /// Default template for messages without a user
+    pub system_template: Option<String>,
+    /// strftime-style format used for the `{timestamp}` placeholder
+    #[serde(default = "default_timestamp_format")]
+    pub timestamp_format: String,
+    /// Messages starting with this are treated as bridge commands
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Self {
+            platform_aliases: HashMap::new(),
+            max_hops: default_max_hops(),
+            template: None,
+            system_template: None,
+            timestamp_format: default_timestamp_format(),
+            command_prefix: default_command_prefix(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Outbox {
+    /// Messages that could not be delivered for this long are discarded, in seconds
+    #[serde(default = "default_outbox_max_age")]
+    pub max_age: u64,
+    /// How often delivering the stored messages is attempted, in seconds
+    #[serde(default = "default_outbox_retry_interval")]
+    pub retry_interval: u64,
+    /// Messages that failed this many times are discarded, even if they are not too old yet
+    #[serde(default = "default_outbox_max_attempts")]
+    pub max_attempts: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct History {
+    /// Whether bridged messages and their deliveries are recorded
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Messages older than this many days are removed from the history
+    #[serde(default = "default_history_retention_days")]
+    pub retention_days: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: default_history_retention_days(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_history_retention_days() -> u64 {
+    30
+}
+
+fn default_outbox_max_age() -> u64 {
+    60 * 60
+}
+
+fn default_outbox_retry_interval() -> u64 {
+    30
+}
+
+fn default_outbox_max_attempts() -> i64 {
+    20
+}
+
+fn default_max_hops() -> u32 {
+    3
+}
+
+fn default_timestamp_format() -> String {
+    "%H:%M".to_owned()
+}
+
+fn default_command_prefix() -> String {
+    "!".to_owned()
 }
@@ -0,0 +1,397 @@
+use crate::{
+    admin_api::{constant_time_eq, has_bearer_token},
+    builder::{PlatformStatus, PlatformStatuses},
+    history::MessageHistory,
+    router::{FilterHits, SharedRouter},
+    DbPool,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+    Form,
+};
+use chrono::DateTime;
+use serde::Deserialize;
+use std::{collections::HashMap, fmt::Write};
+use tracing::{error, warn};
+
+const RECENT_MESSAGES: i64 = 25;
+/// Set by `/login`, holds the hex encoded admin token since browsers cannot send a bearer token
+const TOKEN_COOKIE: &str = "supabridge_admin_token";
+
+/// Read-only status page served at `/`, so the bridge can be checked without shell access.
+/// It shows chat messages and user names, so it requires the admin token.
+#[derive(Clone)]
+pub struct Dashboard {
+    pub message_router: SharedRouter,
+    pub platform_statuses: PlatformStatuses,
+    pub bot_user_ids: HashMap<&'static str, String>,
+    pub history: MessageHistory,
+    pub filter_hits: FilterHits,
+    pub db: DbPool,
+    pub admin_token: String,
+    /// Only send the login cookie over HTTPS, set when the base url uses it
+    pub secure_cookie: bool,
+}
+
+impl Dashboard {
+    pub fn routes(self) -> axum::Router {
+        axum::Router::new()
+            .route("/", get(render_dashboard))
+            .route_layer(middleware::from_fn_with_state(self.clone(), require_login))
+            .route("/login", get(login_page).post(login))
+            .with_state(self)
+    }
+
+    fn has_twitch(&self) -> bool {
+        self.platform_statuses
+            .read()
+            .unwrap()
+            .contains_key("twitch")
+    }
+
+    fn platforms_section(&self, html: &mut String) {
+        let mut statuses: Vec<_> = self
+            .platform_statuses
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, status)| (*name, status.clone()))
+            .collect();
+        statuses.sort_by_key(|(name, _)| *name);
+
+        html.push_str("<h2>Platforms</h2><table><tr><th>Platform</th><th>State</th></tr>");
+        for (name, status) in statuses {
+            let state = match status {
+                PlatformStatus::Running { since } => {
+                    format!("running since {}", since.format("%Y-%m-%d %H:%M:%S UTC"))
+                }
+                PlatformStatus::Restarting { error, attempt } => {
+                    format!("restarting (attempt {attempt}): {error}")
+                }
+                PlatformStatus::Stopped => "stopped".to_owned(),
+            };
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                escape(name),
+                escape(&state)
+            );
+        }
+        html.push_str("</table>");
+    }
+
+    fn bridges_section(&self, html: &mut String) {
+        let router = self.message_router.read().unwrap();
+
+        html.push_str("<h2>Bridges</h2><table><tr><th>Channels</th><th>Direction</th></tr>");
+        for bridge in &router.bridges {
+            let channels: Vec<String> = bridge
+                .channels
+                .iter()
+                .map(|channel| {
+                    let mut entry = escape(&channel.to_string());
+                    if router.state.paused_channels.contains(channel) {
+                        entry.push_str(" <em>(paused)</em>");
+                    }
+                    entry
+                })
+                .collect();
+            let direction = if bridge.bidirectional {
+                "both ways"
+            } else {
+                "from the first channel"
+            };
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{direction}</td></tr>",
+                channels.join("<br>")
+            );
+        }
+        html.push_str("</table>");
+
+        if !router.state.muted_users.is_empty() {
+            let mut muted_users: Vec<_> = router
+                .state
+                .muted_users
+                .iter()
+                .map(|((channel, _), user_name)| escape(&format!("{user_name} in {channel}")))
+                .collect();
+            muted_users.sort();
+            let _ = write!(html, "<p>Muted users: {}</p>", muted_users.join(", "));
+        }
+    }
+
+    async fn traffic_section(&self, html: &mut String) -> anyhow::Result<()> {
+        let entries = self.history.recent(RECENT_MESSAGES).await?;
+
+        html.push_str("<h2>Recent messages</h2>");
+        if entries.is_empty() {
+            html.push_str("<p>No messages in the history</p>");
+            return Ok(());
+        }
+
+        html.push_str(
+            "<table><tr><th>Received</th><th>Channel</th><th>User</th><th>Message</th><th>Deliveries</th></tr>",
+        );
+        for entry in entries {
+            let record = entry.record;
+            let channel = record
+                .channel_name
+                .or(record.channel_id)
+                .map(|channel| format!("{}:{channel}", record.source_platform))
+                .unwrap_or(record.source_platform);
+            let deliveries: Vec<String> = entry
+                .deliveries
+                .iter()
+                .map(|delivery| {
+                    let target = match &delivery.target_channel_id {
+                        Some(channel_id) => format!("{}:{channel_id}", delivery.target_platform),
+                        None => delivery.target_platform.clone(),
+                    };
+                    match &delivery.error {
+                        Some(err) => format!(
+                            "<span class=\"error\">{} failed: {}</span>",
+                            escape(&target),
+                            escape(err)
+                        ),
+                        None => escape(&target),
+                    }
+                })
+                .collect();
+
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                format_timestamp(record.received_at),
+                escape(&channel),
+                escape(record.user_name.as_deref().unwrap_or_default()),
+                escape(&record.contents),
+                deliveries.join("<br>"),
+            );
+        }
+        html.push_str("</table>");
+        Ok(())
+    }
+
+    fn filter_hits_section(&self, html: &mut String) {
+        let hits = self.filter_hits.snapshot();
+
+        html.push_str("<h2>Filtered messages</h2>");
+        if hits.is_empty() {
+            html.push_str("<p>No messages were filtered since startup</p>");
+            return;
+        }
+
+        html.push_str("<table><tr><th>Channel</th><th>Reason</th><th>Messages</th></tr>");
+        for (channel, reason, count) in hits {
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{count}</td></tr>",
+                escape(&channel.to_string()),
+                reason.as_str().replace('_', " "),
+            );
+        }
+        html.push_str("</table>");
+    }
+
+    async fn twitch_section(&self, html: &mut String) -> anyhow::Result<()> {
+        if !self.has_twitch() {
+            return Ok(());
+        }
+
+        // Scopes are stored space separated, as returned by the authorization redirect
+        let logins: HashMap<String, (String, bool)> =
+            sqlx::query!(r#"SELECT user_id as "user_id!", scopes, needs_reauth FROM twitch_login"#)
+                .fetch_all(&self.db)
+                .await?
+                .into_iter()
+                .map(|login| (login.user_id, (login.scopes, login.needs_reauth)))
+                .collect();
+        let has_scope = |scopes: &str, scope: &str| scopes.split(' ').any(|value| value == scope);
+
+        html.push_str("<h2>Twitch authorization</h2>");
+
+        let bot_login = self
+            .bot_user_ids
+            .get("twitch")
+            .and_then(|user_id| logins.get(user_id))
+            .filter(|(scopes, _)| has_scope(scopes, "user:bot"));
+        match bot_login {
+            Some((_, false)) => html.push_str("<p>The bot account is logged in.</p>"),
+            Some((_, true)) => html.push_str(
+                "<p class=\"error\">The bot account's login expired, it has to log in again.</p>",
+            ),
+            None => html.push_str("<p class=\"error\">The bot account is not logged in.</p>"),
+        }
+        html.push_str(
+            "<p><a class=\"button\" href=\"/platform/twitch/auth?mode=user\">Log in as the bot</a></p>",
+        );
+
+        let channels = self
+            .message_router
+            .read()
+            .unwrap()
+            .platform_channels("twitch");
+        html.push_str("<table><tr><th>Channel</th><th>Bot access</th></tr>");
+        for channel_id in channels {
+            let state = match logins.get(&channel_id) {
+                Some((scopes, false)) if has_scope(scopes, "channel:bot") => "authorized",
+                Some((scopes, true)) if has_scope(scopes, "channel:bot") => {
+                    "<span class=\"error\">login expired</span>"
+                }
+                _ => "<span class=\"error\">not authorized</span>",
+            };
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{state}</td></tr>",
+                escape(&channel_id)
+            );
+        }
+        html.push_str("</table>");
+        html.push_str(TWITCH_USER_LINKS);
+        Ok(())
+    }
+}
+
+/// The only part of the dashboard that is public, streamers and linked users log in with their own account
+const TWITCH_USER_LINKS: &str =
+    "<p>Streamers have to allow the bot in their channel by logging in with their own account:</p>\
+    <p><a class=\"button\" href=\"/platform/twitch/auth?mode=channel\">Authorize a channel</a></p>\
+    <p>Users with linked accounts can let the bridge send their messages under their own name:</p>\
+    <p><a class=\"button\" href=\"/platform/twitch/auth?mode=sender\">Send as my account</a></p>";
+
+fn page_start() -> String {
+    String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>supabridge</title><style>\
+        body { font-family: sans-serif; margin: 2em; }\
+        table { border-collapse: collapse; margin-bottom: 1em; }\
+        th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }\
+        .error { color: #b00; }\
+        .button { display: inline-block; padding: 0.4em 0.8em; background: #6441a5; color: #fff; text-decoration: none; border-radius: 4px; }\
+        </style></head><body><h1>supabridge</h1>",
+    )
+}
+
+/// Accepts the admin token as a bearer token like the admin API, or as the cookie set by `/login`
+async fn require_login(
+    State(dashboard): State<Dashboard>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = &dashboard.admin_token;
+    if has_bearer_token(request.headers(), token) || has_token_cookie(request.headers(), token) {
+        next.run(request).await
+    } else {
+        Redirect::to("/login").into_response()
+    }
+}
+
+fn has_token_cookie(headers: &HeaderMap, token: &str) -> bool {
+    let expected = hex_encode(token);
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .any(|(name, value)| {
+            name == TOKEN_COOKIE && constant_time_eq(value.as_bytes(), expected.as_bytes())
+        })
+}
+
+/// Cookie values cannot contain every character a token might have
+fn hex_encode(text: &str) -> String {
+    text.bytes().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+async fn login_page(State(dashboard): State<Dashboard>) -> Html<String> {
+    render_login(&dashboard, false)
+}
+
+fn render_login(dashboard: &Dashboard, failed: bool) -> Html<String> {
+    let mut html = page_start();
+    if failed {
+        html.push_str("<p class=\"error\">Wrong token</p>");
+    }
+    html.push_str(
+        "<form method=\"post\" action=\"/login\">\
+        <label>Admin token <input type=\"password\" name=\"token\" autofocus></label> \
+        <button type=\"submit\">Log in</button></form>",
+    );
+    if dashboard.has_twitch() {
+        html.push_str("<h2>Twitch authorization</h2>");
+        html.push_str(TWITCH_USER_LINKS);
+    }
+    html.push_str("</body></html>");
+    Html(html)
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    token: String,
+}
+
+async fn login(State(dashboard): State<Dashboard>, Form(form): Form<LoginForm>) -> Response {
+    if !constant_time_eq(form.token.as_bytes(), dashboard.admin_token.as_bytes()) {
+        warn!("Failed dashboard login");
+        return (StatusCode::UNAUTHORIZED, render_login(&dashboard, true)).into_response();
+    }
+
+    let mut cookie = format!(
+        "{TOKEN_COOKIE}={}; Path=/; HttpOnly; SameSite=Strict",
+        hex_encode(&dashboard.admin_token)
+    );
+    if dashboard.secure_cookie {
+        cookie.push_str("; Secure");
+    }
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
+}
+
+async fn render_dashboard(
+    State(dashboard): State<Dashboard>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let mut html = page_start();
+
+    dashboard.platforms_section(&mut html);
+    dashboard.bridges_section(&mut html);
+    let sections = async {
+        dashboard.twitch_section(&mut html).await?;
+        dashboard.traffic_section(&mut html).await
+    };
+    if let Err(err) = sections.await {
+        error!("Could not render dashboard: {err:#}");
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+    dashboard.filter_hits_section(&mut html);
+
+    html.push_str("</body></html>");
+    Ok(Html(html))
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,191 @@
+use crate::{
+    message_body::MessageBody,
+    template::{Template, TemplateValues},
+    IncomingMessage,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync::OnceLock};
+
+/// Something that happened in a channel other than a chat message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChannelEvent {
+    Join {
+        user_name: String,
+    },
+    Leave {
+        user_name: String,
+    },
+    Death {
+        user_name: String,
+        cause: Option<String>,
+    },
+    Achievement {
+        user_name: String,
+        achievement: String,
+    },
+    ResearchFinished {
+        technology: String,
+    },
+    StreamOnline,
+    StreamOffline,
+    Raid {
+        user_name: String,
+        viewers: u64,
+    },
+    Subscription {
+        user_name: String,
+        tier: String,
+        gifted: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Join,
+    Leave,
+    Death,
+    Achievement,
+    ResearchFinished,
+    StreamOnline,
+    StreamOffline,
+    Raid,
+    Subscription,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 9] = [
+        Self::Join,
+        Self::Leave,
+        Self::Death,
+        Self::Achievement,
+        Self::ResearchFinished,
+        Self::StreamOnline,
+        Self::StreamOffline,
+        Self::Raid,
+        Self::Subscription,
+    ];
+
+    fn default_template(self) -> &'static str {
+        match self {
+            Self::Join => "{name} joined",
+            Self::Leave => "{name} left",
+            Self::Death => "{name} was killed by {contents|something}",
+            Self::Achievement => "{name} earned the achievement {contents}",
+            Self::ResearchFinished => "Research of {contents} finished",
+            Self::StreamOnline => "{channel|The stream} is now live",
+            Self::StreamOffline => "{channel|The stream} is now offline",
+            Self::Raid => "{name} is raiding with {contents} viewers",
+            Self::Subscription => "{name} subscribed ({contents})",
+        }
+    }
+}
+
+/// Used when a bridge does not set its own template for the kind of event
+pub fn default_event_template(kind: EventKind) -> &'static Template {
+    static DEFAULT_TEMPLATES: OnceLock<HashMap<EventKind, Template>> = OnceLock::new();
+    DEFAULT_TEMPLATES
+        .get_or_init(|| {
+            EventKind::ALL
+                .into_iter()
+                .map(|kind| {
+                    (
+                        kind,
+                        Template::parse_event(kind.default_template()).unwrap(),
+                    )
+                })
+                .collect()
+        })
+        .get(&kind)
+        .unwrap()
+}
+
+impl ChannelEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::Join { .. } => EventKind::Join,
+            Self::Leave { .. } => EventKind::Leave,
+            Self::Death { .. } => EventKind::Death,
+            Self::Achievement { .. } => EventKind::Achievement,
+            Self::ResearchFinished { .. } => EventKind::ResearchFinished,
+            Self::StreamOnline => EventKind::StreamOnline,
+            Self::StreamOffline => EventKind::StreamOffline,
+            Self::Raid { .. } => EventKind::Raid,
+            Self::Subscription { .. } => EventKind::Subscription,
+        }
+    }
+
+    /// The user the event is about, available as `{name}` in event templates
+    pub fn user_name(&self) -> Option<&str> {
+        match self {
+            Self::Join { user_name }
+            | Self::Leave { user_name }
+            | Self::Death { user_name, .. }
+            | Self::Achievement { user_name, .. }
+            | Self::Raid { user_name, .. }
+            | Self::Subscription { user_name, .. } => Some(user_name),
+            Self::ResearchFinished { .. } | Self::StreamOnline | Self::StreamOffline => None,
+        }
+    }
+
+    /// Kind specific information, available as `{contents}` in event templates
+    pub fn details(&self) -> Option<String> {
+        match self {
+            Self::Death { cause, .. } => cause.clone(),
+            Self::Achievement { achievement, .. } => Some(achievement.clone()),
+            Self::ResearchFinished { technology } => Some(technology.clone()),
+            Self::Raid { viewers, .. } => Some(viewers.to_string()),
+            Self::Subscription { tier, gifted, .. } => {
+                if *gifted {
+                    Some(format!("{tier}, gifted"))
+                } else {
+                    Some(tier.clone())
+                }
+            }
+            Self::Join { .. } | Self::Leave { .. } | Self::StreamOnline | Self::StreamOffline => {
+                None
+            }
+        }
+    }
+
+    /// Wraps the event in a message without a user, its body is the default description of the event
+    pub fn into_message(
+        self,
+        id: String,
+        timestamp: DateTime<Utc>,
+        channel_id: Option<String>,
+        channel_name: Option<String>,
+    ) -> IncomingMessage {
+        IncomingMessage {
+            id,
+            timestamp,
+            channel_id,
+            channel_name,
+            user_id: None,
+            user_name: None,
+            body: MessageBody::from_text(&self.to_string()),
+            user_color: None,
+            provenance: None,
+            event: Some(self),
+            user_is_admin: false,
+        }
+    }
+}
+
+impl fmt::Display for ChannelEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let details = self.details().unwrap_or_default();
+        let values = TemplateValues {
+            platform: "",
+            name: self.user_name(),
+            color: None,
+            channel: None,
+            timestamp: "",
+            message_id: "",
+            contents: &details,
+        };
+        f.write_str(&default_event_template(self.kind()).render(&values))
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    builder::{PlatformStatus, PlatformStatuses},
+    DbPool,
+};
+use axum::{extract::State, http::StatusCode, routing::get, Json};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::{collections::BTreeMap, collections::HashMap, sync::Arc, time::Duration};
+
+/// Checks taking longer than this are reported as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of one of a platform's own health checks, e.g. whether it is connected
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    pub error: Option<String>,
+}
+
+impl HealthCheck {
+    pub fn new(name: &'static str, result: Result<(), String>) -> Self {
+        Self {
+            name,
+            error: result.err(),
+        }
+    }
+}
+
+/// Runs the health checks of a platform
+pub type HealthChecker = Arc<dyn Fn() -> BoxFuture<'static, Vec<HealthCheck>> + Send + Sync>;
+
+/// Serves `/healthz` and `/readyz` for container orchestration
+#[derive(Clone)]
+pub struct Health {
+    pub db: DbPool,
+    pub platform_statuses: PlatformStatuses,
+    pub checkers: HashMap<&'static str, HealthChecker>,
+    /// Platforms that have to be healthy for the bridge to be ready, all of them when not set
+    pub required_platforms: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    healthy: bool,
+    database_error: Option<String>,
+    platforms: BTreeMap<&'static str, PlatformHealth>,
+}
+
+#[derive(Serialize)]
+struct PlatformHealth {
+    healthy: bool,
+    required: bool,
+    status: Option<PlatformStatus>,
+    checks: Vec<HealthCheck>,
+}
+
+impl Health {
+    pub fn routes(self) -> axum::Router {
+        axum::Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .with_state(self)
+    }
+
+    async fn report(&self) -> HealthReport {
+        let database_error =
+            match tokio::time::timeout(CHECK_TIMEOUT, sqlx::query("SELECT 1").execute(&self.db))
+                .await
+            {
+                Ok(Ok(_)) => None,
+                Ok(Err(err)) => Some(err.to_string()),
+                Err(_) => Some("Timed out".to_owned()),
+            };
+
+        let mut platforms = BTreeMap::new();
+        for (name, checker) in &self.checkers {
+            let checks = match tokio::time::timeout(CHECK_TIMEOUT, checker()).await {
+                Ok(checks) => checks,
+                Err(_) => vec![HealthCheck::new("checks", Err("Timed out".to_owned()))],
+            };
+            let status = self.platform_statuses.read().unwrap().get(name).cloned();
+            let healthy = matches!(status, Some(PlatformStatus::Running { .. }))
+                && checks.iter().all(|check| check.error.is_none());
+            let required = self
+                .required_platforms
+                .as_ref()
+                .is_none_or(|required| required.iter().any(|platform| platform == name));
+
+            platforms.insert(
+                *name,
+                PlatformHealth {
+                    healthy,
+                    required,
+                    status,
+                    checks,
+                },
+            );
+        }
+
+        let healthy = database_error.is_none()
+            && platforms
+                .values()
+                .all(|platform| platform.healthy || !platform.required);
+        HealthReport {
+            healthy,
+            database_error,
+            platforms,
+        }
+    }
+}
+
+/// Only fails when the database is unusable, a degraded platform recovers by itself
+async fn healthz(State(health): State<Health>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report().await;
+    let status = if report.database_error.is_none() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Fails when the database or any required platform is degraded
+async fn readyz(State(health): State<Health>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report().await;
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
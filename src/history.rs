@@ -0,0 +1,292 @@
+use crate::{
+    admin_api::require_token, config, ChannelIdentifier, DbPool, IncomingMessage, OutgoingMessage,
+};
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::get,
+    Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Records bridged messages and their deliveries in the `message_history` and `message_delivery` tables
+#[derive(Clone)]
+pub struct MessageHistory {
+    db: DbPool,
+    enabled: bool,
+    retention: chrono::Duration,
+}
+
+impl MessageHistory {
+    pub fn new(db: &DbPool, config: &config::History) -> Self {
+        Self {
+            db: db.clone(),
+            enabled: config.enabled,
+            retention: chrono::Duration::days(config.retention_days as i64),
+        }
+    }
+
+    /// Stores a received message, returns its history id if it was recorded
+    pub async fn record(&self, source_platform: &str, msg: &IncomingMessage) -> Option<i64> {
+        // Copies sent by the bridge itself are already recorded as deliveries of the original message
+        if !self.enabled || msg.provenance.is_some() {
+            return None;
+        }
+
+        let received_at = msg.timestamp.timestamp();
+        let contents = msg.body.plain_text();
+        let result = sqlx::query_scalar!(
+            "INSERT INTO message_history
+            (source_platform, message_id, channel_id, channel_name, user_id, user_name, contents, received_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING id",
+            source_platform,
+            msg.id,
+            msg.channel_id,
+            msg.channel_name,
+            msg.user_id,
+            msg.user_name,
+            contents,
+            received_at,
+        )
+        .fetch_one(&self.db)
+        .await;
+
+        match result {
+            Ok(id) => Some(id),
+            Err(err) => {
+                error!("Could not record message in history: {err}");
+                None
+            }
+        }
+    }
+
+    /// Records an attempt to deliver the message, `error` is set when it failed
+    pub async fn record_delivery(
+        &self,
+        target_platform: &str,
+        outgoing_msg: &OutgoingMessage,
+        error: Option<&anyhow::Error>,
+    ) {
+        let Some(history_id) = outgoing_msg.history_id else {
+            return;
+        };
+        let attempted_at = Utc::now().timestamp();
+        let error = error.map(|err| format!("{err:#}"));
+
+        if let Err(err) = sqlx::query!(
+            "INSERT INTO message_delivery
+            (message_id, target_platform, target_channel_id, attempted_at, error)
+            VALUES (?, ?, ?, ?, ?)",
+            history_id,
+            target_platform,
+            outgoing_msg.target_channel_id,
+            attempted_at,
+            error,
+        )
+        .execute(&self.db)
+        .await
+        {
+            error!("Could not record message delivery: {err}");
+        }
+    }
+
+    /// Removes messages older than the retention period
+    pub async fn prune(&self) -> anyhow::Result<()> {
+        let min_received_at = (Utc::now() - self.retention).timestamp();
+        let removed = sqlx::query!(
+            "DELETE FROM message_history WHERE received_at < ?",
+            min_received_at
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not prune message history")?
+        .rows_affected();
+
+        if removed > 0 {
+            info!("Removed {removed} messages from history");
+        }
+        Ok(())
+    }
+
+    /// Requires the admin token like the admin API, the history contains every bridged message
+    pub fn api_routes(self, admin_token: String) -> axum::Router {
+        axum::Router::new()
+            .route("/:channel", get(get_channel_history))
+            .layer(middleware::from_fn_with_state(admin_token, require_token))
+            .with_state(self)
+    }
+
+    async fn fetch_page(
+        &self,
+        channel: &ChannelIdentifier,
+        params: &HistoryParams,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        let before = params.before.unwrap_or(i64::MAX);
+        let limit = params
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        let records = match params.search.as_deref() {
+            Some(search) => {
+                let match_query = fts_query(search);
+                sqlx::query_as!(
+                    HistoryRecord,
+                    "SELECT h.id, h.source_platform, h.message_id, h.channel_id, h.channel_name,
+                        h.user_id, h.user_name, h.contents, h.received_at
+                    FROM message_history_fts f
+                    JOIN message_history h ON h.id = f.rowid
+                    WHERE message_history_fts MATCH ?
+                        AND h.source_platform = ? AND h.channel_id IS ? AND h.id < ?
+                    ORDER BY h.id DESC
+                    LIMIT ?",
+                    match_query,
+                    channel.platform,
+                    channel.value,
+                    before,
+                    limit,
+                )
+                .fetch_all(&self.db)
+                .await?
+            }
+            None => {
+                sqlx::query_as!(
+                    HistoryRecord,
+                    "SELECT id, source_platform, message_id, channel_id, channel_name,
+                        user_id, user_name, contents, received_at
+                    FROM message_history
+                    WHERE source_platform = ? AND channel_id IS ? AND id < ?
+                    ORDER BY id DESC
+                    LIMIT ?",
+                    channel.platform,
+                    channel.value,
+                    before,
+                    limit,
+                )
+                .fetch_all(&self.db)
+                .await?
+            }
+        };
+
+        self.with_deliveries(records).await
+    }
+
+    /// The latest messages of all channels
+    pub async fn recent(&self, limit: i64) -> anyhow::Result<Vec<HistoryEntry>> {
+        let records = sqlx::query_as!(
+            HistoryRecord,
+            "SELECT id, source_platform, message_id, channel_id, channel_name,
+                user_id, user_name, contents, received_at
+            FROM message_history
+            ORDER BY id DESC
+            LIMIT ?",
+            limit,
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        self.with_deliveries(records).await
+    }
+
+    async fn with_deliveries(
+        &self,
+        records: Vec<HistoryRecord>,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::with_capacity(records.len());
+        for record in records {
+            let deliveries = sqlx::query_as!(
+                DeliveryEntry,
+                "SELECT target_platform, target_channel_id, attempted_at, error
+                FROM message_delivery WHERE message_id = ?
+                ORDER BY attempted_at",
+                record.id
+            )
+            .fetch_all(&self.db)
+            .await?;
+
+            entries.push(HistoryEntry { record, deliveries });
+        }
+        Ok(entries)
+    }
+}
+
+/// Every word has to be present in the message, FTS query syntax is not exposed
+fn fts_query(search: &str) -> String {
+    search
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    /// Only return messages with a lower id, used for paging
+    before: Option<i64>,
+    limit: Option<i64>,
+    search: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryRecord {
+    pub id: i64,
+    pub source_platform: String,
+    pub message_id: Option<String>,
+    pub channel_id: Option<String>,
+    pub channel_name: Option<String>,
+    pub user_id: Option<String>,
+    pub user_name: Option<String>,
+    pub contents: String,
+    pub received_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct DeliveryEntry {
+    pub target_platform: String,
+    pub target_channel_id: Option<String>,
+    pub attempted_at: i64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub record: HistoryRecord,
+    pub deliveries: Vec<DeliveryEntry>,
+}
+
+async fn get_channel_history(
+    Path(channel): Path<String>,
+    Query(params): Query<HistoryParams>,
+    State(history): State<MessageHistory>,
+) -> Result<Json<Vec<HistoryEntry>>, (StatusCode, String)> {
+    let channel: ChannelIdentifier = channel.parse().unwrap();
+
+    if params
+        .search
+        .as_deref()
+        .is_some_and(|search| search.trim().is_empty())
+    {
+        return Err((StatusCode::BAD_REQUEST, "Empty search query".to_owned()));
+    }
+
+    history
+        .fetch_page(&channel, &params)
+        .await
+        .map(Json)
+        .map_err(|err| {
+            error!("Could not fetch message history: {err:#}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not fetch message history".to_owned(),
+            )
+        })
+}
@@ -1,33 +1,73 @@
 #![warn(clippy::all)]
+mod admin_api;
 mod builder;
+mod commands;
 mod config;
+mod dashboard;
+mod events;
+mod health;
+mod history;
+mod message_body;
+mod message_mapping;
+mod metrics;
+mod outbox;
 mod platforms;
 mod router;
+mod template;
+#[cfg(test)]
+mod test_util;
+mod user_filters;
+mod user_links;
 
+use admin_api::AdminApi;
 use anyhow::{anyhow, Context};
-use axum::routing::get;
-use builder::PlatformsBuilder;
+use builder::{Delivery, PlatformsBuilder};
+use chrono::{DateTime, Utc};
+use commands::Command;
 use config::Config;
-use futures::{future::select_all, TryStreamExt};
-use router::MessageRouter;
+use dashboard::Dashboard;
+use events::ChannelEvent;
+use futures::future::{join_all, select_all};
+use health::Health;
+use history::MessageHistory;
+use message_body::MessageBody;
+use message_mapping::MessageMappings;
+use metrics::MetricsEndpoint;
+use notify::{RecommendedWatcher, Watcher};
+use router::{FilterHits, MessageRouter, RouterContext, SharedRouter};
+use serde::{Deserialize, Serialize};
 use sqlx::{
     sqlite::{SqliteConnectOptions, SqlitePoolOptions},
     Pool, Sqlite,
 };
-use std::{collections::HashMap, convert::Infallible, fmt, fs, str::FromStr};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+};
 use tower_http::{limit::RequestBodyLimitLayer, trace::TraceLayer};
-use tracing::{debug, error, info};
+use tracing::{error, info, warn};
 
-use crate::config::FilterMode;
+use crate::{user_filters::UserFilters, user_links::UserLinks};
 
+const CONFIG_PATH: &str = "config.toml";
 const API_BODY_SIZE_LIMIT: usize = 64 * 1024;
+const USER_FILTERS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 type DbPool = Pool<Sqlite>;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    let raw_config = fs::read_to_string("config.toml").context("Could not read config file")?;
-    let config: Config = toml::from_str(&raw_config).context("Could not parse config")?;
+    let config = read_config()?;
 
     tracing_subscriber::fmt()
         .with_env_filter(&config.general.log_level)
@@ -44,9 +84,23 @@ async fn main() -> anyhow::Result<()> {
     sqlx::migrate!().run(&db_pool).await?;
     info!("DB migrations finished");
 
-    let message_router = MessageRouter::new(&config.bridge)?;
+    let message_router: SharedRouter = Arc::new(RwLock::new(MessageRouter::new(&config)?));
 
-    let mut platforms = PlatformsBuilder::new(&config, &message_router, &db_pool);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let history = MessageHistory::new(&db_pool, &config.history);
+    let mappings = MessageMappings::new(&db_pool);
+
+    let mut platforms = PlatformsBuilder::new(
+        &config,
+        &message_router,
+        &db_pool,
+        shutdown_rx.clone(),
+        Delivery {
+            history: history.clone(),
+            mappings: mappings.clone(),
+        },
+    );
     platforms.init_platform::<platforms::Twitch>().await?;
     platforms.init_platform::<platforms::Factorio>().await?;
 
@@ -59,95 +113,170 @@ async fn main() -> anyhow::Result<()> {
     );
 
     let mut handles = platforms.platform_handles;
+    let sender_handles = platforms.sender_handles;
 
     let mut incoming_message_rx = platforms.incoming_messages_rx;
     let message_senders = platforms.message_senders;
-    let zws_support = platforms.zws_support;
-    let platform_aliases = config.message.platform_aliases.clone();
-    let channel_links = message_router.channel_links.clone();
+    let metrics_endpoint = MetricsEndpoint {
+        message_senders: message_senders.clone(),
+        incoming_messages_tx: platforms.incoming_messages_tx.clone(),
+    };
+
+    let user_links = UserLinks::load(&db_pool).await?;
+    info!("Loaded {} linked identities", user_links.len());
+
+    let user_filters = UserFilters::load(&db_pool).await?;
+    info!("Loaded {} user filters", user_filters.len());
+
+    let refreshed_user_filters = user_filters.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(USER_FILTERS_REFRESH_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(err) = refreshed_user_filters.refresh().await {
+                error!("{err:#}");
+            }
+        }
+    });
 
-    let user_links = load_user_links(&db_pool).await?;
-    info!("Loaded {} user links", user_links.len());
+    let pruned_history = history.clone();
+    let pruned_mappings = mappings.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = pruned_history.prune().await {
+                error!("{err:#}");
+            }
+            if let Err(err) = pruned_mappings.prune().await {
+                error!("{err:#}");
+            }
+        }
+    });
 
+    let filter_hits = FilterHits::default();
+    let router_ctx = RouterContext {
+        zws_support: platforms.zws_support,
+        deletion_support: platforms.deletion_support,
+        body_renderers: platforms.body_renderers,
+        commands: platforms.commands,
+        default_templates: platforms.default_templates,
+        user_links: user_links.clone(),
+        user_filters,
+        filter_hits: filter_hits.clone(),
+    };
+
+    let admin_api = AdminApi {
+        message_router: message_router.clone(),
+        message_senders: message_senders.clone(),
+        platform_statuses: platforms.platform_statuses.clone(),
+        user_links,
+    };
+    let health = Health {
+        db: db_pool.clone(),
+        platform_statuses: platforms.platform_statuses.clone(),
+        checkers: platforms.health_checkers,
+        required_platforms: config.general.required_platforms.clone(),
+    };
+
+    let _config_watcher =
+        spawn_config_reloader(message_router.clone(), platforms.channel_updaters)?;
+
+    let routing_router = message_router.clone();
+    let routing_history = history.clone();
+    let routing_mappings = mappings;
+    let mut routing_shutdown_rx = shutdown_rx.clone();
     let send_handle = tokio::spawn(async move {
-        while let Some((source_platform, incoming_msg)) = incoming_message_rx.recv().await {
-            let identifier = ChannelIdentifier {
-                platform: source_platform.to_owned(),
-                value: incoming_msg.channel_id.clone(),
+        let mut shutting_down = false;
+        loop {
+            let received = tokio::select! {
+                received = incoming_message_rx.recv() => received,
+                _ = routing_shutdown_rx.wait_for(|shutdown| *shutdown), if !shutting_down => {
+                    // Messages that were already received are still routed before the queues get closed
+                    incoming_message_rx.close();
+                    shutting_down = true;
+                    continue;
+                }
+            };
+            let Some((source_platform, incoming_event)) = received else {
+                break;
             };
 
-            if let Some(target_channels) = channel_links.get(&identifier) {
-                debug!("Mirroring message {incoming_msg:?} to channels {target_channels:?}");
-                'target_channels: for target_channel in target_channels {
-                    let platform = platform_aliases
-                        .get(source_platform)
-                        .map(|s| s.as_str())
-                        .unwrap_or(source_platform);
-
-                    let content = match incoming_msg.user_name.clone() {
-                        Some(mut name) => {
-                            let platform_supports_zws = *zws_support
-                                .get(target_channel.channel.platform.as_str())
-                                .unwrap();
-
-                            if target_channel.insert_zws && name.len() > 1 && platform_supports_zws
-                            {
-                                let magic_char = char::from_u32(0x000E0000).unwrap();
-                                name.insert(1, magic_char);
-                            }
-
-                            format!("[{platform}] {name}: {}", incoming_msg.contents)
-                        }
-                        None => format!("[{platform}] {}", incoming_msg.contents),
-                    };
-
-                    let filter_haystack = match target_channel.filter_mode {
-                        FilterMode::FinalMessage => &content,
-                        FilterMode::SourceMessage => &incoming_msg.contents,
-                    };
-                    for exclude_filter in &target_channel.exclude_filters {
-                        if exclude_filter.is_match(filter_haystack) {
-                            debug!(
-                                "Message '{content}' to {} filtered out by {exclude_filter}",
-                                target_channel.channel
-                            );
-                            continue 'target_channels;
-                        }
+            let outgoing_events = match incoming_event {
+                IncomingEvent::Message(incoming_msg) => {
+                    // Copies sent by the bridge itself were already counted on their source platform
+                    if incoming_msg.provenance.is_none() {
+                        metrics::MESSAGES_RECEIVED.inc(&[
+                            source_platform,
+                            incoming_msg.channel_id.as_deref().unwrap_or_default(),
+                        ]);
+                    }
+                    let history_id = routing_history.record(source_platform, &incoming_msg).await;
+
+                    let command = routing_router.read().unwrap().find_command(
+                        &router_ctx,
+                        source_platform,
+                        &incoming_msg,
+                    );
+                    if let Some(command) = command {
+                        let reply_sender = message_senders.get(source_platform).cloned();
+                        tokio::spawn(answer_command(
+                            command,
+                            routing_router.clone(),
+                            source_platform,
+                            incoming_msg,
+                            reply_sender,
+                        ));
+                        continue;
                     }
 
-                    let sender_user_id = incoming_msg
-                        .user_id
-                        .as_ref()
-                        .and_then(|source_user_id| {
-                            user_links.get(&(
-                                UserIdentifier {
-                                    platform: source_platform.to_owned(),
-                                    user_id: source_user_id.to_owned(),
-                                },
-                                target_channel.channel.platform.clone(),
-                            ))
+                    routing_router
+                        .read()
+                        .unwrap()
+                        .route(&router_ctx, source_platform, &incoming_msg)
+                        .into_iter()
+                        .map(|(target_platform, mut outgoing_message)| {
+                            outgoing_message.history_id = history_id;
+                            (
+                                target_platform,
+                                OutgoingEvent::Message(Box::new(outgoing_message)),
+                            )
                         })
-                        .cloned();
-                    let content = match &sender_user_id {
-                        Some(_) => incoming_msg.contents.clone(),
-                        None => content,
+                        .collect::<Vec<_>>()
+                }
+                IncomingEvent::Deletion(deletion) => {
+                    let copies = match routing_mappings.find(source_platform, &deletion).await {
+                        Ok(copies) => copies,
+                        Err(err) => {
+                            error!("Could not look up copies of deleted messages: {err:#}");
+                            continue;
+                        }
                     };
 
-                    let outgoing_message = OutgoingMessage {
-                        source_platform_name: platform.to_owned(),
-                        content,
-                        target_channel_id: target_channel.channel.value.clone(),
-                        sender_user_id,
-                        source_msg: incoming_msg.clone(),
-                    };
+                    routing_router
+                        .read()
+                        .unwrap()
+                        .route_deletion(&router_ctx, source_platform, &deletion, copies)
+                        .into_iter()
+                        .map(|(target_platform, outgoing_deletion)| {
+                            (target_platform, OutgoingEvent::Deletion(outgoing_deletion))
+                        })
+                        .collect()
+                }
+            };
 
-                    match message_senders.get(target_channel.channel.platform.as_str()) {
-                        Some(sender) => sender.send(outgoing_message).await.unwrap(),
-                        None => error!(
-                            "Could not get sender for platform {} (is it configured?)",
-                            target_channel.channel.platform
-                        ),
+            for (target_platform, outgoing_event) in outgoing_events {
+                match message_senders.get(target_platform.as_str()) {
+                    Some(sender) => {
+                        // The sending task is gone if it panicked, the other platforms still get their messages
+                        if sender.send(outgoing_event).await.is_err() {
+                            error!("Could not queue message for platform {target_platform}, it is no longer sending");
+                        }
                     }
+                    None => error!(
+                        "Could not get sender for platform {target_platform} (is it configured?)"
+                    ),
                 }
             }
         }
@@ -155,9 +284,32 @@ async fn main() -> anyhow::Result<()> {
     });
     handles.push(send_handle);
 
-    let web_app = axum::Router::new()
-        .route("/", get("XD"))
-        .nest("/platform", platforms.api_router)
+    let mut web_app = axum::Router::new()
+        .merge(metrics_endpoint.routes())
+        .merge(health.routes())
+        .nest("/platform", platforms.api_router);
+    match config.general.admin_token.clone() {
+        Some(admin_token) => {
+            let dashboard = Dashboard {
+                message_router: message_router.clone(),
+                platform_statuses: platforms.platform_statuses,
+                bot_user_ids: platforms.bot_user_ids,
+                history: history.clone(),
+                filter_hits,
+                db: db_pool.clone(),
+                admin_token: admin_token.clone(),
+                secure_cookie: config.general.base_url.starts_with("https://"),
+            };
+            web_app = web_app
+                .merge(dashboard.routes())
+                .nest("/history", history.api_routes(admin_token.clone()))
+                .nest("/admin", admin_api.routes(admin_token));
+        }
+        None => info!(
+            "No admin token configured, the admin API, dashboard and history API are disabled"
+        ),
+    }
+    let web_app = web_app
         .layer(TraceLayer::new_for_http())
         .layer(RequestBodyLimitLayer::new(API_BODY_SIZE_LIMIT))
         .layer(axum::Extension(db_pool));
@@ -168,20 +320,192 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Web server listening on {}", config.general.listen_address);
 
+    let mut web_shutdown_rx = shutdown_rx;
     let web_handle = tokio::spawn(async move {
         axum::serve(listener, web_app)
+            .with_graceful_shutdown(async move {
+                let _ = web_shutdown_rx.wait_for(|shutdown| *shutdown).await;
+            })
             .await
             .expect("Web server error");
         ("web", Ok(()))
     });
     handles.push(web_handle);
 
-    let (result, _, _) = select_all(handles).await;
-    let (name, result) = result.unwrap();
-    Err(anyhow!("Worker '{name}' exited unexpectedly: {result:?}"))
+    tokio::select! {
+        (result, _, _) = select_all(handles.iter_mut()) => {
+            let (name, result) = result.unwrap();
+            return Err(anyhow!("Worker '{name}' exited unexpectedly: {result:?}"));
+        }
+        result = shutdown_signal() => result?,
+    }
+
+    info!("Shutting down");
+    shutdown_tx.send_replace(true);
+
+    let shutdown_timeout = Duration::from_secs(config.general.shutdown_timeout);
+    let stopped = async {
+        join_all(handles).await;
+        join_all(sender_handles).await;
+    };
+    match tokio::time::timeout(shutdown_timeout, stopped).await {
+        Ok(()) => info!("Shutdown complete"),
+        Err(_) => warn!("Timed out waiting for queued messages to be delivered"),
+    }
+    Ok(())
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+/// Runs the command and sends the reply back to the channel it was sent in
+async fn answer_command(
+    command: Command,
+    message_router: SharedRouter,
+    source_platform: &'static str,
+    incoming_msg: Box<IncomingMessage>,
+    reply_sender: Option<mpsc::Sender<OutgoingEvent>>,
+) {
+    let reply = match command.execute(&message_router).await {
+        Ok(reply) => reply,
+        Err(err) => {
+            error!("Could not run command {:?}: {err:#}", incoming_msg.body);
+            "Could not run the command".to_owned()
+        }
+    };
+
+    let outgoing_msg =
+        message_router
+            .read()
+            .unwrap()
+            .direct_message(source_platform, &incoming_msg, reply);
+    let Some(reply_sender) = reply_sender else {
+        return;
+    };
+    if reply_sender
+        .send(OutgoingEvent::Message(Box::new(outgoing_msg)))
+        .await
+        .is_err()
+    {
+        warn!("Could not send command reply, platform {source_platform} is shutting down");
+    }
+}
+
+/// Waits for SIGTERM (e.g. from `docker stop`) or Ctrl+C
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate()).context("Could not listen for SIGTERM")?;
+    tokio::select! {
+        _ = terminate.recv() => info!("Got SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result.context("Could not listen for Ctrl+C")?;
+            info!("Got Ctrl+C");
+        }
+    }
+    Ok(())
+}
+
+fn read_config() -> anyhow::Result<Config> {
+    let raw_config = fs::read_to_string(CONFIG_PATH).context("Could not read config file")?;
+    let config: Config = toml::from_str(&raw_config).context("Could not parse config")?;
+
+    if let Some(platform) = config
+        .general
+        .required_platforms
+        .iter()
+        .flatten()
+        .find(|platform| !config.platforms.contains_key(platform.as_str()))
+    {
+        return Err(anyhow!("Required platform {platform} is not configured"));
+    }
+    Ok(config)
+}
+
+/// Reloads the bridge and message settings on SIGHUP or when the config file changes.
+/// Other settings still require a restart.
+fn spawn_config_reloader(
+    message_router: SharedRouter,
+    channel_updaters: HashMap<&'static str, watch::Sender<Vec<String>>>,
+) -> anyhow::Result<RecommendedWatcher> {
+    let (reload_tx, mut reload_rx) = mpsc::channel(1);
+
+    let mut hangup = signal(SignalKind::hangup()).context("Could not listen for SIGHUP")?;
+    let signal_reload_tx = reload_tx.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Got SIGHUP, reloading config");
+            let _ = signal_reload_tx.try_send(());
+        }
+    });
+
+    // Editors often replace the file instead of writing to it, so the whole directory is watched
+    let mut watcher = RecommendedWatcher::new(
+        move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                let config_changed = (event.kind.is_modify() || event.kind.is_create())
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == Path::new(CONFIG_PATH).file_name());
+                if config_changed {
+                    let _ = reload_tx.try_send(());
+                }
+            }
+            Err(err) => error!("Could not handle config FS event: {err}"),
+        },
+        notify::Config::default(),
+    )
+    .context("Could not create config watcher")?;
+    watcher
+        .watch(Path::new("."), notify::RecursiveMode::NonRecursive)
+        .context("Could not watch config file")?;
+
+    tokio::spawn(async move {
+        while reload_rx.recv().await.is_some() {
+            match reload_router(&message_router, &channel_updaters) {
+                Ok(()) => info!("Config reloaded"),
+                Err(err) => error!("Could not reload config: {err:#}"),
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+fn reload_router(
+    message_router: &SharedRouter,
+    channel_updaters: &HashMap<&'static str, watch::Sender<Vec<String>>>,
+) -> anyhow::Result<()> {
+    let config = read_config()?;
+    let mut new_router = MessageRouter::new(&config)?;
+    // Platforms are only started once, the bridges can't use one that was added since
+    if let Some(channel) = new_router
+        .bridges
+        .iter()
+        .flat_map(|bridge| &bridge.channels)
+        .find(|channel| !channel_updaters.contains_key(channel.platform.as_str()))
+    {
+        return Err(anyhow!(
+            "Platform {} of channel {channel} is not running, restart to add it",
+            channel.platform
+        ));
+    }
+
+    for (platform, updater) in channel_updaters {
+        let channels = new_router.platform_channels(platform);
+        updater.send_if_modified(|current| {
+            if *current != channels {
+                *current = channels;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    let mut current_router = message_router.write().unwrap();
+    new_router.state = std::mem::take(&mut current_router.state);
+    *current_router = new_router;
+    Ok(())
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Serialize, Deserialize)]
 struct ChannelIdentifier {
     platform: String,
     value: Option<String>,
@@ -220,37 +544,87 @@ impl fmt::Display for ChannelIdentifier {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+enum IncomingEvent {
+    Message(Box<IncomingMessage>),
+    /// Messages were removed on the source platform, e.g. by a moderator
+    Deletion(MessageDeletion),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct IncomingMessage {
+    /// Id of the message on the source platform, generated by the bridge if the platform has none
+    id: String,
+    /// When the message was received
+    timestamp: DateTime<Utc>,
     channel_id: Option<String>,
+    channel_name: Option<String>,
     user_id: Option<String>,
     user_name: Option<String>,
-    contents: String,
+    body: MessageBody,
     // hex
     user_color: Option<String>,
+    /// Set when the message is a copy that was previously sent by the bridge
+    provenance: Option<Provenance>,
+    /// Set when the message describes an event instead of being sent by a user
+    event: Option<ChannelEvent>,
+    /// Whether the user is allowed to use moderator commands, e.g. a Twitch moderator or Factorio admin
+    #[serde(default)]
+    user_is_admin: bool,
 }
 
 #[derive(Debug)]
+struct MessageDeletion {
+    channel_id: Option<String>,
+    target: DeletionTarget,
+}
+
+#[derive(Debug)]
+enum DeletionTarget {
+    Message {
+        message_id: String,
+    },
+    /// All messages sent by the user
+    User {
+        user_id: String,
+    },
+}
+
+#[derive(Debug)]
+enum OutgoingEvent {
+    Message(Box<OutgoingMessage>),
+    Deletion(OutgoingDeletion),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct OutgoingMessage {
+    source_platform: String,
     source_msg: IncomingMessage,
-    source_platform_name: String,
     target_channel_id: Option<String>,
     sender_user_id: Option<String>,
+    /// The message formatted with the target channel's template
     content: String,
+    /// The message text without any formatting, used when sending as a linked user
+    unformatted_content: String,
+    provenance: Provenance,
+    /// Id of the source message in the history, if it was recorded
+    history_id: Option<i64>,
+    /// Set from the target channel's max_message_age, the message is dropped if it is still waiting in the outbox then
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
 }
 
-// (user, target platform) -> target user id
-async fn load_user_links(db: &DbPool) -> anyhow::Result<HashMap<(UserIdentifier, String), String>> {
-    sqlx::query!("SELECT * FROM user_link")
-        .fetch(db)
-        .map_ok(|record| {
-            let source_user = UserIdentifier {
-                platform: record.source_platform,
-                user_id: record.source_user_id,
-            };
-            ((source_user, record.target_platform), record.target_user_id)
-        })
-        .try_collect()
-        .await
-        .context("DB error")
+/// Removes a copy of a message that was previously sent by the bridge
+#[derive(Debug)]
+struct OutgoingDeletion {
+    target_channel_id: Option<String>,
+    message_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Provenance {
+    origin_platform: String,
+    origin_message_id: Option<String>,
+    hop_count: u32,
+    visited_channels: Vec<ChannelIdentifier>,
 }
@@ -0,0 +1,224 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, sync::OnceLock};
+
+/// Structured message contents, rendered by every platform in its own syntax
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageBody {
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Segment {
+    Text(String),
+    Emote {
+        name: String,
+    },
+    Mention {
+        /// Id of the mentioned user on the source platform, if known
+        user_id: Option<String>,
+        name: String,
+    },
+    Link {
+        url: String,
+    },
+    Formatted {
+        style: Style,
+        segments: Vec<Segment>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Style {
+    Bold,
+    /// Hex color or a color name
+    Color(String),
+}
+
+/// Renders the body in a platform's syntax
+pub type BodyRenderer = fn(&MessageBody) -> String;
+
+fn link_regex() -> &'static Regex {
+    static LINK_REGEX: OnceLock<Regex> = OnceLock::new();
+    LINK_REGEX.get_or_init(|| Regex::new(r"https?://\S+").unwrap())
+}
+
+impl MessageBody {
+    /// Splits plain text into text and link segments
+    pub fn from_text(text: &str) -> Self {
+        let mut body = Self::default();
+        body.push_text(text);
+        body
+    }
+
+    pub fn push(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+
+    pub fn push_text(&mut self, text: &str) {
+        let mut last = 0;
+        for link in link_regex().find_iter(text) {
+            self.push_plain(&text[last..link.start()]);
+            self.push(Segment::Link {
+                url: link.as_str().to_owned(),
+            });
+            last = link.end();
+        }
+        self.push_plain(&text[last..]);
+    }
+
+    fn push_plain(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        match self.segments.last_mut() {
+            Some(Segment::Text(previous)) => previous.push_str(text),
+            _ => self.segments.push(Segment::Text(text.to_owned())),
+        }
+    }
+
+    /// The body without any formatting, used for filtering and storage
+    pub fn plain_text(&self) -> String {
+        let mut output = String::new();
+        write_plain(&self.segments, &mut output);
+        output
+    }
+
+    /// Applies a transformation to every text segment, keeping the other segments as they are
+    pub fn map_text(&self, f: &impl Fn(&str) -> Cow<'_, str>) -> Self {
+        Self {
+            segments: map_segments(&self.segments, &|segment| match segment {
+                Segment::Text(text) => Segment::Text(f(text).into_owned()),
+                other => other.clone(),
+            }),
+        }
+    }
+
+    /// (user id, name) of the mentioned users whose id is known
+    pub fn mentioned_users(&self) -> Vec<(String, String)> {
+        let mut users = Vec::new();
+        collect_mentions(&self.segments, &mut users);
+        users
+    }
+
+    /// Replaces every mention with the segment returned for its user id and name
+    pub fn map_mentions(&self, f: &impl Fn(Option<&str>, &str) -> Segment) -> Self {
+        Self {
+            segments: map_segments(&self.segments, &|segment| match segment {
+                Segment::Mention { user_id, name } => f(user_id.as_deref(), name),
+                other => other.clone(),
+            }),
+        }
+    }
+}
+
+fn write_plain(segments: &[Segment], output: &mut String) {
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => output.push_str(text),
+            Segment::Emote { name } => output.push_str(name),
+            Segment::Mention { name, .. } => {
+                output.push('@');
+                output.push_str(name);
+            }
+            Segment::Link { url } => output.push_str(url),
+            Segment::Formatted { segments, .. } => write_plain(segments, output),
+        }
+    }
+}
+
+fn collect_mentions(segments: &[Segment], users: &mut Vec<(String, String)>) {
+    for segment in segments {
+        match segment {
+            Segment::Mention {
+                user_id: Some(user_id),
+                name,
+            } => users.push((user_id.clone(), name.clone())),
+            Segment::Formatted { segments, .. } => collect_mentions(segments, users),
+            _ => {}
+        }
+    }
+}
+
+/// Applies `f` to every segment that is not formatting
+fn map_segments(segments: &[Segment], f: &impl Fn(&Segment) -> Segment) -> Vec<Segment> {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Formatted { style, segments } => Segment::Formatted {
+                style: style.clone(),
+                segments: map_segments(segments, f),
+            },
+            other => f(other),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mention, text};
+
+    #[test]
+    fn from_text_splits_links() {
+        let body = MessageBody::from_text("see https://example.com/a?b=c and http://x.y");
+        assert_eq!(
+            body.segments,
+            [
+                text("see "),
+                Segment::Link {
+                    url: "https://example.com/a?b=c".to_owned()
+                },
+                text(" and "),
+                Segment::Link {
+                    url: "http://x.y".to_owned()
+                },
+            ]
+        );
+        assert!(MessageBody::from_text("").segments.is_empty());
+    }
+
+    #[test]
+    fn push_text_joins_text_segments() {
+        let mut body = MessageBody::from_text("a");
+        body.push_text("b");
+        assert_eq!(body.segments, [text("ab")]);
+    }
+
+    #[test]
+    fn plain_text_flattens_segments() {
+        let body = MessageBody {
+            segments: vec![
+                text("hi "),
+                mention(Some("1"), "bob"),
+                Segment::Formatted {
+                    style: Style::Bold,
+                    segments: vec![
+                        text(" "),
+                        Segment::Emote {
+                            name: "Kappa".to_owned(),
+                        },
+                    ],
+                },
+            ],
+        };
+        assert_eq!(body.plain_text(), "hi @bob Kappa");
+    }
+
+    #[test]
+    fn mentions_in_formatting_are_found() {
+        let body = MessageBody {
+            segments: vec![
+                mention(None, "unknown"),
+                Segment::Formatted {
+                    style: Style::Color("red".to_owned()),
+                    segments: vec![mention(Some("1"), "bob")],
+                },
+            ],
+        };
+        assert_eq!(body.mentioned_users(), [("1".to_owned(), "bob".to_owned())]);
+
+        let mapped = body.map_mentions(&|_, name| text(&name.to_uppercase()));
+        assert_eq!(mapped.plain_text(), "UNKNOWNBOB");
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{DbPool, DeletionTarget, MessageDeletion, OutgoingMessage};
+use anyhow::Context;
+use chrono::Utc;
+use tracing::debug;
+
+/// Copies older than this are forgotten, so deleting their source message no longer removes them
+const MAPPING_RETENTION_HOURS: i64 = 24;
+
+/// Keeps track of which messages the bridge sent for each source message in the `message_mapping` table
+#[derive(Clone)]
+pub struct MessageMappings {
+    db: DbPool,
+}
+
+/// A message sent by the bridge
+pub struct MessageCopy {
+    pub target_platform: String,
+    pub target_channel_id: Option<String>,
+    pub target_message_id: String,
+}
+
+impl MessageMappings {
+    pub fn new(db: &DbPool) -> Self {
+        Self { db: db.clone() }
+    }
+
+    pub async fn record(
+        &self,
+        target_platform: &str,
+        outgoing_msg: &OutgoingMessage,
+        target_message_id: &str,
+    ) -> anyhow::Result<()> {
+        let created_at = Utc::now().timestamp();
+        let source_msg = &outgoing_msg.source_msg;
+
+        sqlx::query!(
+            "INSERT INTO message_mapping
+            (source_platform, source_channel_id, source_message_id, source_user_id,
+                target_platform, target_channel_id, target_message_id, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            outgoing_msg.source_platform,
+            source_msg.channel_id,
+            source_msg.id,
+            source_msg.user_id,
+            target_platform,
+            outgoing_msg.target_channel_id,
+            target_message_id,
+            created_at,
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not record message mapping")?;
+        Ok(())
+    }
+
+    /// Finds the copies of the deleted messages
+    pub async fn find(
+        &self,
+        source_platform: &str,
+        deletion: &MessageDeletion,
+    ) -> anyhow::Result<Vec<MessageCopy>> {
+        let copies = match &deletion.target {
+            DeletionTarget::Message { message_id } => {
+                sqlx::query_as!(
+                    MessageCopy,
+                    "SELECT target_platform, target_channel_id, target_message_id
+                    FROM message_mapping
+                    WHERE source_platform = ? AND source_channel_id IS ? AND source_message_id = ?",
+                    source_platform,
+                    deletion.channel_id,
+                    message_id,
+                )
+                .fetch_all(&self.db)
+                .await?
+            }
+            DeletionTarget::User { user_id } => {
+                sqlx::query_as!(
+                    MessageCopy,
+                    "SELECT target_platform, target_channel_id, target_message_id
+                    FROM message_mapping
+                    WHERE source_platform = ? AND source_channel_id IS ? AND source_user_id = ?",
+                    source_platform,
+                    deletion.channel_id,
+                    user_id,
+                )
+                .fetch_all(&self.db)
+                .await?
+            }
+        };
+        debug!("Found {} copies for deletion {deletion:?}", copies.len());
+        Ok(copies)
+    }
+
+    pub async fn prune(&self) -> anyhow::Result<()> {
+        let min_created_at =
+            (Utc::now() - chrono::Duration::hours(MAPPING_RETENTION_HOURS)).timestamp();
+        sqlx::query!(
+            "DELETE FROM message_mapping WHERE created_at < ?",
+            min_created_at
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not prune message mappings")?;
+        Ok(())
+    }
+}
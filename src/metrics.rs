@@ -0,0 +1,256 @@
+use crate::{IncomingEvent, OutgoingEvent};
+use axum::{extract::State, http::header, response::IntoResponse, routing::get};
+use std::{collections::BTreeMap, collections::HashMap, fmt::Write, sync::Mutex};
+use tokio::sync::mpsc;
+
+pub static MESSAGES_RECEIVED: Counter = Counter::new(
+    "supabridge_messages_received_total",
+    "Messages received from platforms, not counting copies sent by the bridge",
+    &["platform", "channel"],
+);
+pub static MESSAGES_DELIVERED: Counter = Counter::new(
+    "supabridge_messages_delivered_total",
+    "Messages sent to platforms",
+    &["platform"],
+);
+pub static MESSAGES_FILTERED: Counter = Counter::new(
+    "supabridge_messages_filtered_total",
+    "Messages not mirrored to a channel, filter is the pattern of the matching exclude filter",
+    &["channel", "reason", "filter"],
+);
+pub static SEND_ERRORS: Counter = Counter::new(
+    "supabridge_send_errors_total",
+    "Messages that could not be sent to platforms",
+    &["platform"],
+);
+pub static RCON_RECONNECTS: Counter = Counter::new(
+    "supabridge_rcon_reconnects_total",
+    "Reconnects to the Factorio RCON server after a failed command",
+    &[],
+);
+pub static TWITCH_RATE_LIMIT_RETRIES: Counter = Counter::new(
+    "supabridge_twitch_rate_limit_retries_total",
+    "Messages sent again after Twitch answered with 429 Too Many Requests",
+    &[],
+);
+pub static DELIVERY_LATENCY: Histogram = Histogram::new(
+    "supabridge_delivery_latency_seconds",
+    "Time from receiving a message on the source platform until its copy was sent",
+    &["platform"],
+    &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0],
+);
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The label values have to be given in the order of the counter's labels
+    pub fn inc(&self, label_values: &[&str]) {
+        debug_assert_eq!(label_values.len(), self.labels.len());
+        let key = label_values.iter().map(|value| value.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "counter");
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            write_sample(out, self.name, self.labels, label_values, None, value);
+        }
+    }
+}
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, HistogramValues>>,
+}
+
+#[derive(Default)]
+struct HistogramValues {
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        buckets: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, label_values: &[&str], value: f64) {
+        debug_assert_eq!(label_values.len(), self.labels.len());
+        let key = label_values.iter().map(|value| value.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let entry = values.entry(key).or_insert_with(|| HistogramValues {
+            bucket_counts: vec![0; self.buckets.len()],
+            ..Default::default()
+        });
+
+        for (bucket, bucket_count) in self.buckets.iter().zip(&mut entry.bucket_counts) {
+            if value <= *bucket {
+                *bucket_count += 1;
+            }
+        }
+        entry.sum += value;
+        entry.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "histogram");
+        let bucket_name = format!("{}_bucket", self.name);
+        let sum_name = format!("{}_sum", self.name);
+        let count_name = format!("{}_count", self.name);
+
+        for (label_values, values) in self.values.lock().unwrap().iter() {
+            for (bucket, bucket_count) in self.buckets.iter().zip(&values.bucket_counts) {
+                let le = bucket.to_string();
+                write_sample(
+                    out,
+                    &bucket_name,
+                    self.labels,
+                    label_values,
+                    Some(&le),
+                    bucket_count,
+                );
+            }
+            write_sample(
+                out,
+                &bucket_name,
+                self.labels,
+                label_values,
+                Some("+Inf"),
+                values.count,
+            );
+            write_sample(out, &sum_name, self.labels, label_values, None, values.sum);
+            write_sample(
+                out,
+                &count_name,
+                self.labels,
+                label_values,
+                None,
+                values.count,
+            );
+        }
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &[&str],
+    label_values: &[String],
+    le: Option<&str>,
+    value: impl std::fmt::Display,
+) {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .zip(label_values)
+        .map(|(label, value)| format!("{label}=\"{}\"", escape_label_value(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+
+    if pairs.is_empty() {
+        let _ = writeln!(out, "{name} {value}");
+    } else {
+        let _ = writeln!(out, "{name}{{{}}} {value}", pairs.join(","));
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the metrics in the Prometheus text format
+#[derive(Clone)]
+pub struct MetricsEndpoint {
+    pub message_senders: HashMap<&'static str, mpsc::Sender<OutgoingEvent>>,
+    pub incoming_messages_tx: mpsc::Sender<(&'static str, IncomingEvent)>,
+}
+
+impl MetricsEndpoint {
+    pub fn routes(self) -> axum::Router {
+        axum::Router::new()
+            .route("/metrics", get(render_metrics))
+            .with_state(self)
+    }
+}
+
+async fn render_metrics(State(endpoint): State<MetricsEndpoint>) -> impl IntoResponse {
+    let mut out = String::new();
+
+    MESSAGES_RECEIVED.render(&mut out);
+    MESSAGES_DELIVERED.render(&mut out);
+    MESSAGES_FILTERED.render(&mut out);
+    SEND_ERRORS.render(&mut out);
+    RCON_RECONNECTS.render(&mut out);
+    TWITCH_RATE_LIMIT_RETRIES.render(&mut out);
+    DELIVERY_LATENCY.render(&mut out);
+
+    // Queue depths are read from the channels instead of being tracked separately
+    let incoming_tx = &endpoint.incoming_messages_tx;
+    write_header(
+        &mut out,
+        "supabridge_incoming_queue_depth",
+        "Received messages waiting to be routed",
+        "gauge",
+    );
+    let _ = writeln!(
+        out,
+        "supabridge_incoming_queue_depth {}",
+        incoming_tx.max_capacity() - incoming_tx.capacity()
+    );
+
+    write_header(
+        &mut out,
+        "supabridge_outgoing_queue_depth",
+        "Messages waiting to be sent to a platform",
+        "gauge",
+    );
+    let mut senders: Vec<_> = endpoint.message_senders.iter().collect();
+    senders.sort_by_key(|(platform, _)| **platform);
+    for (platform, sender) in senders {
+        let _ = writeln!(
+            out,
+            "supabridge_outgoing_queue_depth{{platform=\"{platform}\"}} {}",
+            sender.max_capacity() - sender.capacity()
+        );
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
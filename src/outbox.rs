@@ -0,0 +1,348 @@
+use crate::{
+    builder::Delivery,
+    config,
+    platforms::{is_permanent, ChatPlatform},
+    router::SharedRouter,
+    DbPool, OutgoingEvent, OutgoingMessage,
+};
+use anyhow::Context;
+use chrono::Utc;
+use std::{collections::HashSet, time::Duration};
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use tracing::{debug, error, warn};
+
+/// Target channels with stored messages, tracked so sending does not have to check the database
+type PendingChannels = HashSet<Option<String>>;
+
+/// Stores messages that could not be delivered in the `outbox` table, so they can be retried in order
+/// once the platform works again. The order is kept per channel, a failing channel does not hold back the others.
+#[derive(Clone)]
+pub struct Outbox {
+    db: DbPool,
+    message_router: SharedRouter,
+    max_age: chrono::Duration,
+    retry_interval: Duration,
+    max_attempts: i64,
+}
+
+impl Outbox {
+    pub fn new(db: &DbPool, message_router: &SharedRouter, config: &config::Outbox) -> Self {
+        Self {
+            db: db.clone(),
+            message_router: message_router.clone(),
+            max_age: chrono::Duration::seconds(config.max_age as i64),
+            retry_interval: Duration::from_secs(config.retry_interval),
+            max_attempts: config.max_attempts,
+        }
+    }
+
+    /// Sends the platform's messages until the queue is closed, going through the outbox for channels
+    /// that have stored messages. Deletions are not stored, they only apply to messages that were already delivered.
+    pub async fn send_all<T: ChatPlatform>(
+        &self,
+        platform: &T,
+        delivery: &Delivery,
+        outgoing_rx: &mut mpsc::Receiver<OutgoingEvent>,
+    ) {
+        let mut pending = match self.pending_channels(T::NAME).await {
+            Ok(pending) => pending,
+            Err(err) => {
+                error!("Could not read outbox: {err:#}");
+                PendingChannels::new()
+            }
+        };
+        let mut retry_interval = tokio::time::interval(self.retry_interval);
+        retry_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                outgoing_event = outgoing_rx.recv() => {
+                    let outgoing_msg = match outgoing_event {
+                        Some(OutgoingEvent::Message(outgoing_msg)) => *outgoing_msg,
+                        Some(OutgoingEvent::Deletion(deletion)) => {
+                            delivery.delete(platform, deletion).await;
+                            continue;
+                        }
+                        None => break,
+                    };
+                    let channel_id = outgoing_msg.target_channel_id.clone();
+
+                    // Messages that are already stored for the channel have to be sent first to keep the order
+                    if pending.contains(&channel_id) {
+                        if !self.flush_channel(platform, delivery, &channel_id).await {
+                            self.store(T::NAME, &outgoing_msg).await;
+                            continue;
+                        }
+                        pending.remove(&channel_id);
+                    }
+
+                    match delivery.send(platform, outgoing_msg.clone()).await {
+                        Ok(()) => {}
+                        Err(err) if is_permanent(&err) => {
+                            error!("Could not send message to platform {}, dropping it: {err:#}", T::NAME);
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Could not send message to platform {}, storing it in the outbox: {err:#}",
+                                T::NAME
+                            );
+                            self.store(T::NAME, &outgoing_msg).await;
+                            pending.insert(channel_id);
+                        }
+                    }
+                }
+                _ = retry_interval.tick(), if !pending.is_empty() => {
+                    for channel_id in pending.clone() {
+                        if self.flush_channel(platform, delivery, &channel_id).await {
+                            pending.remove(&channel_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the channel's stored messages in order until one fails, returns whether none are left afterwards.
+    /// Messages are dropped when the platform rejects them or after too many attempts.
+    async fn flush_channel<T: ChatPlatform>(
+        &self,
+        platform: &T,
+        delivery: &Delivery,
+        channel_id: &Option<String>,
+    ) -> bool {
+        if let Err(err) = self.discard_undeliverable(T::NAME, channel_id).await {
+            error!("Could not remove undeliverable messages from the outbox: {err:#}");
+        }
+
+        loop {
+            let (id, attempts, outgoing_msg) = match self.next(T::NAME, channel_id).await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return true,
+                Err(err) => {
+                    error!("Could not read outbox: {err:#}");
+                    return false;
+                }
+            };
+
+            match delivery.send(platform, outgoing_msg).await {
+                Ok(()) => {}
+                Err(err) if is_permanent(&err) => {
+                    error!(
+                        "Could not deliver stored message to platform {}, dropping it: {err:#}",
+                        T::NAME
+                    );
+                }
+                Err(err) if attempts + 1 >= self.max_attempts => {
+                    error!(
+                        "Could not deliver stored message to platform {} after {} attempts, dropping it: {err:#}",
+                        T::NAME,
+                        attempts + 1
+                    );
+                }
+                Err(err) => {
+                    debug!(
+                        "Could not deliver stored message to platform {}: {err:#}",
+                        T::NAME
+                    );
+                    if let Err(err) = self.record_attempt(id).await {
+                        error!("Could not update outbox entry: {err:#}");
+                    }
+                    return false;
+                }
+            }
+
+            if let Err(err) = self.remove(id).await {
+                error!("Could not remove message from the outbox: {err:#}");
+                return false;
+            }
+        }
+    }
+
+    async fn store(&self, platform: &str, outgoing_msg: &OutgoingMessage) {
+        if let Err(err) = self.push(platform, outgoing_msg).await {
+            error!("Could not store message in the outbox: {err:#}");
+        }
+    }
+
+    async fn push(&self, platform: &str, outgoing_msg: &OutgoingMessage) -> anyhow::Result<()> {
+        let message = serde_json::to_string(outgoing_msg)?;
+        let channel_id = outgoing_msg.target_channel_id.as_deref();
+        let created_at = Utc::now().timestamp();
+
+        sqlx::query!(
+            "INSERT INTO outbox (platform, channel_id, created_at, message) VALUES (?, ?, ?, ?)",
+            platform,
+            channel_id,
+            created_at,
+            message
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn pending_channels(&self, platform: &str) -> anyhow::Result<PendingChannels> {
+        let channel_ids = sqlx::query_scalar!(
+            "SELECT DISTINCT channel_id FROM outbox WHERE platform = ?",
+            platform
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(channel_ids.into_iter().collect())
+    }
+
+    /// Removes the channel's messages that are too old, or all of them when the channel is no longer bridged
+    async fn discard_undeliverable(
+        &self,
+        platform: &str,
+        channel_id: &Option<String>,
+    ) -> anyhow::Result<()> {
+        let bridged = match channel_id {
+            Some(channel_id) => self
+                .message_router
+                .read()
+                .unwrap()
+                .bridged_channels(platform)
+                .contains(channel_id),
+            None => true,
+        };
+        let min_created_at = if bridged {
+            (Utc::now() - self.max_age).timestamp()
+        } else {
+            i64::MAX
+        };
+
+        let channel_id = channel_id.as_deref();
+        let discarded = sqlx::query!(
+            "DELETE FROM outbox WHERE platform = ? AND channel_id IS ? AND created_at < ?",
+            platform,
+            channel_id,
+            min_created_at
+        )
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+
+        if discarded > 0 && bridged {
+            warn!("Discarded {discarded} messages to platform {platform} that could not be delivered in time");
+        } else if discarded > 0 {
+            warn!(
+                "Discarded {discarded} messages to channel {platform}:{} that is no longer bridged",
+                channel_id.unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    /// Gets the oldest stored message for the channel with its number of failed attempts, skipping expired ones
+    async fn next(
+        &self,
+        platform: &str,
+        channel_id: &Option<String>,
+    ) -> anyhow::Result<Option<(i64, i64, OutgoingMessage)>> {
+        let channel_id = channel_id.as_deref();
+        loop {
+            let Some(record) = sqlx::query!(
+                "SELECT id, attempts, message FROM outbox WHERE platform = ? AND channel_id IS ?
+                ORDER BY id LIMIT 1",
+                platform,
+                channel_id
+            )
+            .fetch_optional(&self.db)
+            .await?
+            else {
+                return Ok(None);
+            };
+
+            match serde_json::from_str::<OutgoingMessage>(&record.message) {
+                Ok(outgoing_msg)
+                    if outgoing_msg
+                        .expires_at
+                        .is_some_and(|expires_at| expires_at < Utc::now()) =>
+                {
+                    debug!(
+                        "Discarding outbox entry {} older than the channel's max_message_age",
+                        record.id
+                    );
+                    self.remove(record.id).await?;
+                }
+                Ok(outgoing_msg) => return Ok(Some((record.id, record.attempts, outgoing_msg))),
+                Err(err) => {
+                    error!("Discarding invalid outbox entry {}: {err}", record.id);
+                    self.remove(record.id).await?;
+                }
+            }
+        }
+    }
+
+    async fn record_attempt(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query!("UPDATE outbox SET attempts = attempts + 1 WHERE id = ?", id)
+            .execute(&self.db)
+            .await
+            .context("Could not count failed attempt")?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query!("DELETE FROM outbox WHERE id = ?", id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{router::MessageRouter, test_util::test_db};
+    use std::sync::{Arc, RwLock};
+
+    async fn outbox(config: &str) -> Outbox {
+        let config: config::Config = toml::from_str(config).unwrap();
+        let message_router = Arc::new(RwLock::new(MessageRouter::new(&config).unwrap()));
+        Outbox::new(
+            &test_db().await,
+            &message_router,
+            &toml::from_str("").unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_for_one_way_bridge_targets() {
+        // Twitch is down, so messages from Factorio are waiting in the outbox
+        let outbox = outbox(
+            r#"
+            [general]
+            base_url = "http://localhost"
+            [platforms.factorio]
+            [platforms.twitch]
+            [[bridge]]
+            channels = ["factorio", "twitch:1"]
+            bidirectional = false
+            "#,
+        )
+        .await;
+        for channel_id in ["1", "2"] {
+            sqlx::query(
+                "INSERT INTO outbox (platform, channel_id, created_at, message) VALUES ('twitch', ?, ?, '{}')",
+            )
+            .bind(channel_id)
+            .bind(Utc::now().timestamp())
+            .execute(&outbox.db)
+            .await
+            .unwrap();
+        }
+
+        for channel_id in ["1", "2"] {
+            outbox
+                .discard_undeliverable("twitch", &Some(channel_id.to_owned()))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            outbox.pending_channels("twitch").await.unwrap(),
+            PendingChannels::from([Some("1".to_owned())])
+        );
+    }
+}
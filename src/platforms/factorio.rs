@@ -1,18 +1,49 @@
 use super::ChatPlatform;
-use crate::{DbPool, IncomingMessage, OutgoingMessage};
-use anyhow::Context;
+use crate::{
+    commands::{CommandRequest, CommandSpec},
+    events::ChannelEvent,
+    health::HealthCheck,
+    message_body::{MessageBody, Segment, Style},
+    metrics, DbPool, IncomingEvent, IncomingMessage, OutgoingMessage,
+};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
 use notify::{RecommendedWatcher, Watcher};
+use regex::Regex;
 use serde::Deserialize;
 use std::{
     fs::File,
     io::{Read, Seek, SeekFrom},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
 };
-use tokio::{net::TcpStream, select, sync::mpsc, task::JoinHandle};
 use tracing::{debug, error, info};
 
+const PLAYER_LIST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Health checks send a command themselves when the last one is older than this
+const RCON_PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Waiting for the player list, which the server writes to the log
+type PlayerListRequests = Arc<std::sync::Mutex<Vec<oneshot::Sender<String>>>>;
+
+/// When the last RCON command was sent, with its error if it failed
+type LastRconResult = std::sync::Mutex<Option<(DateTime<Utc>, Result<(), String>)>>;
+
 pub struct Factorio {
     config: Config,
+    rcon_client: Mutex<Option<rcon::Connection<TcpStream>>>,
+    player_list_requests: PlayerListRequests,
+    log_watcher_running: AtomicBool,
+    last_rcon_result: LastRconResult,
 }
 
 impl Factorio {
@@ -23,6 +54,52 @@ impl Factorio {
             .await
             .context("Could not connect to RCON")
     }
+
+    async fn rcon_cmd(&self, cmd: &str) -> anyhow::Result<()> {
+        let result = self.send_rcon_cmd(cmd).await;
+        *self.last_rcon_result.lock().unwrap() = Some((
+            Utc::now(),
+            result
+                .as_ref()
+                .map(|_| ())
+                .map_err(|err| format!("{err:#}")),
+        ));
+        result
+    }
+
+    async fn send_rcon_cmd(&self, cmd: &str) -> anyhow::Result<()> {
+        let mut rcon_client = self.rcon_client.lock().await;
+        let result = match rcon_client.as_mut() {
+            Some(client) => client.cmd(cmd).await.map_err(anyhow::Error::from),
+            None => Err(anyhow!("Not connected")),
+        };
+
+        if let Err(err) = result {
+            error!("Could not send command to server: {err:#}");
+            info!("Attempting to reconect");
+
+            *rcon_client = None;
+            metrics::RCON_RECONNECTS.inc(&[]);
+            let new_client = self.connect_rcon().await.context("Could not reconnect")?;
+            rcon_client
+                .insert(new_client)
+                .cmd(cmd)
+                .await
+                .context("Could not send command even after a reconnect")?;
+        }
+        Ok(())
+    }
+
+    async fn player_list(&self) -> anyhow::Result<String> {
+        let (tx, rx) = oneshot::channel();
+        self.player_list_requests.lock().unwrap().push(tx);
+
+        self.rcon_cmd("/bridge-player-list").await?;
+        tokio::time::timeout(PLAYER_LIST_TIMEOUT, rx)
+            .await
+            .context("The server did not respond with the player list")?
+            .context("Log watcher stopped")
+    }
 }
 
 impl ChatPlatform for Factorio {
@@ -35,64 +112,224 @@ impl ChatPlatform for Factorio {
         _channel_ids: Vec<String>,
         _db: &DbPool,
     ) -> anyhow::Result<Self> {
-        Ok(Self { config })
+        Ok(Self {
+            config,
+            rcon_client: Mutex::new(None),
+            player_list_requests: PlayerListRequests::default(),
+            log_watcher_running: AtomicBool::new(false),
+            last_rcon_result: LastRconResult::default(),
+        })
     }
 
-    async fn run(
-        self,
-        incoming_message_tx: mpsc::Sender<IncomingMessage>,
-        mut outgoing_message_rx: mpsc::Receiver<OutgoingMessage>,
-    ) -> anyhow::Result<()> {
-        let mut rcon_client = self.connect_rcon().await?;
+    async fn run(&self, incoming_message_tx: mpsc::Sender<IncomingEvent>) -> anyhow::Result<()> {
+        let rcon_client = self.connect_rcon().await?;
+        *self.rcon_client.lock().await = Some(rcon_client);
 
-        let mut log_handle = start_log_watcher(
+        // Holding on to the watcher here makes the log reading thread exit when the platform is stopped
+        let (_watcher, log_handle) = start_log_watcher(
             self.config.bridge_output_log_path.clone(),
             incoming_message_tx,
+            self.player_list_requests.clone(),
+            self.config.admin_list_path.clone(),
         )?;
+        self.log_watcher_running.store(true, Ordering::Relaxed);
+        let result = log_handle.await;
+        self.log_watcher_running.store(false, Ordering::Relaxed);
+        result.context("Log watcher panicked")??;
+        Err(anyhow!("Log watcher stopped"))
+    }
 
-        loop {
-            select! {
-                Some(msg) = outgoing_message_rx.recv() => {
-                    let cmd = if msg.source_msg.contents.starts_with("!players ") || msg.source_msg.contents == "!players" {
-                        String::from("/bridge-player-list")
-                    } else {
-                        let user_text = match msg.source_msg.user_name {
-                            Some(name) => match msg.source_msg.user_color {
-                                Some(color) => {
-                                    format!("[color=#{color}]{name}:[/color] {}", msg.source_msg.contents)
-                                }
-                                None => format!("{name}: {}", msg.source_msg.contents)
-                            }
-                            None => msg.content.to_string()
-                        };
+    async fn send_msg(&self, msg: OutgoingMessage) -> anyhow::Result<Option<String>> {
+        self.rcon_cmd(&format!("/puppet {}", msg.content)).await?;
+        Ok(None)
+    }
 
-                        format!("/puppet [{}] {user_text}", msg.source_platform_name)
-                    };
+    async fn run_command(&self, request: CommandRequest) -> anyhow::Result<String> {
+        match request.name.as_str() {
+            "players" => self.player_list().await,
+            other => Err(anyhow!("Unknown command {other}")),
+        }
+    }
 
-                    if let Err(err) = rcon_client.cmd(&cmd).await {
-                        error!("Could not send message to server: {err}");
-                        info!("Attempting to reconect");
+    async fn shutdown(&self, offline_notice: Option<&str>) -> anyhow::Result<()> {
+        let Some(mut rcon_client) = self.rcon_client.lock().await.take() else {
+            return Ok(());
+        };
 
-                        match self.connect_rcon().await {
-                            Ok(new_client) => {
-                                rcon_client = new_client;
+        if let Some(notice) = offline_notice {
+            rcon_client
+                .cmd(&format!("/puppet {notice}"))
+                .await
+                .context("Could not send offline notice")?;
+        }
+        info!("Closing RCON connection");
+        Ok(())
+    }
 
-                                if let Err(err) = rcon_client.cmd(&cmd).await {
-                                    error!("Could not send message even after a reconnect: {err}");
-                                }
-                            }
-                            Err(err) => error!("Could not reconnect: {err:#}"),
-                        }
-                    }
-                },
-                log_result = &mut log_handle => log_result.unwrap()?,
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        let last_rcon_result = self.last_rcon_result.lock().unwrap().clone();
+        // An open connection can still be dead, so the server has to have answered recently
+        let rcon_result = match last_rcon_result {
+            Some((sent_at, result))
+                if (Utc::now() - sent_at).to_std().unwrap_or_default() < RCON_PING_INTERVAL =>
+            {
+                result
             }
-        }
+            _ => self
+                .rcon_cmd("/time")
+                .await
+                .map_err(|err| format!("{err:#}")),
+        };
+        let log_watcher_running = self.log_watcher_running.load(Ordering::Relaxed);
+
+        vec![
+            HealthCheck::new("rcon", rcon_result),
+            HealthCheck::new(
+                "log_watcher",
+                log_watcher_running
+                    .then_some(())
+                    .ok_or_else(|| "Not running".to_owned()),
+            ),
+        ]
+    }
+
+    fn commands() -> &'static [CommandSpec] {
+        &[CommandSpec {
+            name: "players",
+            description: "lists the players on the server",
+        }]
     }
 
     fn supports_zws() -> bool {
         false
     }
+
+    fn default_template() -> &'static str {
+        "[{platform}] [color=#{color|ffffff}]{name}:[/color] {contents}"
+    }
+
+    fn render_body(body: &MessageBody) -> String {
+        let mut output = String::new();
+        write_rich_text(&body.segments, &mut output);
+        output
+    }
+}
+
+fn write_rich_text(segments: &[Segment], output: &mut String) {
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => output.push_str(text),
+            Segment::Emote { name } => output.push_str(name),
+            Segment::Mention { name, .. } => {
+                output.push_str("[font=default-bold]@");
+                output.push_str(name);
+                output.push_str("[/font]");
+            }
+            Segment::Link { url } => output.push_str(url),
+            Segment::Formatted {
+                style: Style::Bold,
+                segments,
+            } => {
+                output.push_str("[font=default-bold]");
+                write_rich_text(segments, output);
+                output.push_str("[/font]");
+            }
+            Segment::Formatted {
+                style: Style::Color(color),
+                segments,
+            } => {
+                output.push_str("[color=");
+                output.push_str(color);
+                output.push(']');
+                write_rich_text(segments, output);
+                output.push_str("[/color]");
+            }
+        }
+    }
+}
+
+fn rich_text_tag_regex() -> &'static Regex {
+    static TAG_REGEX: OnceLock<Regex> = OnceLock::new();
+    TAG_REGEX.get_or_init(|| Regex::new(r"\[(/?)(color|font)(?:=([^\]]*))?\]").unwrap())
+}
+
+/// Turns the color and font tags of Factorio rich text into formatting, other tags (e.g. `[gps=...]`) are kept as text
+fn parse_rich_text(text: &str) -> MessageBody {
+    // Each level holds the style of an open tag and the body inside of it
+    let mut stack: Vec<(Option<Style>, MessageBody)> = vec![(None, MessageBody::default())];
+    let mut last = 0;
+
+    for captures in rich_text_tag_regex().captures_iter(text) {
+        let tag = captures.get(0).unwrap();
+        let current = &mut stack.last_mut().unwrap().1;
+        push_chat_text(current, &text[last..tag.start()]);
+        last = tag.end();
+
+        let is_closing = !captures[1].is_empty();
+        let tag_name = &captures[2];
+        let value = captures.get(3).map(|value| value.as_str());
+
+        if is_closing {
+            let closes_current = match &stack.last().unwrap().0 {
+                Some(Style::Bold) => tag_name == "font",
+                Some(Style::Color(_)) => tag_name == "color",
+                None => false,
+            };
+            if closes_current {
+                close_tag(&mut stack);
+                continue;
+            }
+        } else {
+            let style = match (tag_name, value) {
+                ("color", Some(color)) => Some(Style::Color(color.to_owned())),
+                ("font", Some("default-bold" | "default-semibold")) => Some(Style::Bold),
+                _ => None,
+            };
+            if let Some(style) = style {
+                stack.push((Some(style), MessageBody::default()));
+                continue;
+            }
+        }
+
+        // Unsupported or unmatched tags stay as they are
+        stack.last_mut().unwrap().1.push_text(tag.as_str());
+    }
+    push_chat_text(&mut stack.last_mut().unwrap().1, &text[last..]);
+
+    // Factorio applies unclosed tags until the end of the message
+    while stack.len() > 1 {
+        close_tag(&mut stack);
+    }
+    stack.pop().unwrap().1
+}
+
+fn mention_regex() -> &'static Regex {
+    static MENTION_REGEX: OnceLock<Regex> = OnceLock::new();
+    MENTION_REGEX.get_or_init(|| Regex::new(r"(?:^|\s)(@([\w-]+))").unwrap())
+}
+
+/// Players are identified by their name, so `@name` mentions can be resolved directly
+fn push_chat_text(body: &mut MessageBody, text: &str) {
+    let mut last = 0;
+    for captures in mention_regex().captures_iter(text) {
+        let mention = captures.get(1).unwrap();
+        body.push_text(&text[last..mention.start()]);
+        body.push(Segment::Mention {
+            user_id: Some(captures[2].to_owned()),
+            name: captures[2].to_owned(),
+        });
+        last = mention.end();
+    }
+    body.push_text(&text[last..]);
+}
+
+fn close_tag(stack: &mut Vec<(Option<Style>, MessageBody)>) {
+    let (style, body) = stack.pop().unwrap();
+    let parent = &mut stack.last_mut().unwrap().1;
+    parent.push(Segment::Formatted {
+        style: style.unwrap(),
+        segments: body.segments,
+    });
 }
 
 #[derive(Deserialize, Debug)]
@@ -100,35 +337,63 @@ pub struct Config {
     pub bridge_output_log_path: PathBuf,
     pub rcon_address: String,
     pub rcon_password: String,
+    /// The server's `server-adminlist.json`, players on it can use moderator commands
+    pub admin_list_path: Option<PathBuf>,
 }
 
 fn start_log_watcher(
     log_path: PathBuf,
-    mut incoming_tx: mpsc::Sender<IncomingMessage>,
-) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    mut incoming_tx: mpsc::Sender<IncomingEvent>,
+    player_list_requests: PlayerListRequests,
+    admin_list_path: Option<PathBuf>,
+) -> anyhow::Result<(RecommendedWatcher, JoinHandle<anyhow::Result<()>>)> {
     let mut file = File::open(&log_path).context("Could not open log file")?;
     // Start reading from the end of the file
     file.seek(SeekFrom::End(0))?;
 
     let (tx, rx) = std::sync::mpsc::channel();
 
-    let handle = tokio::task::spawn_blocking(move || {
-        let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())
-            .context("Could not create file watcher")?;
+    let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())
+        .context("Could not create file watcher")?;
+    watcher
+        .watch(&log_path, notify::RecursiveMode::NonRecursive)
+        .context("Could not watch log file")?;
+    debug!("Registered watcher for log file at {log_path:?}");
 
+    let mut admin_list = admin_list_path.map(AdminList::load);
+    if let Some(admin_list) = &admin_list {
+        // The server might replace the file instead of writing to it, so its directory is watched
         watcher
-            .watch(&log_path, notify::RecursiveMode::NonRecursive)
-            .context("Could not watch log file")?;
-        debug!("Registered watcher for log file at {log_path:?}");
+            .watch(admin_list.directory(), notify::RecursiveMode::NonRecursive)
+            .context("Could not watch admin list")?;
+        debug!("Registered watcher for admin list at {:?}", admin_list.path);
+    }
 
+    // The event stream ends when the watcher is dropped
+    let handle = tokio::task::spawn_blocking(move || {
         for res in rx {
             match res {
                 Ok(event) => {
-                    if event.kind.is_modify() {
+                    if let Some(admin_list) = admin_list
+                        .as_mut()
+                        .filter(|admin_list| admin_list.is_changed_by(&event))
+                    {
+                        admin_list.reload();
+                    } else if event.kind.is_modify() {
                         let mut new_contents = String::new();
                         match file.read_to_string(&mut new_contents) {
                             Ok(_) => {
-                                process_log(&new_contents, &mut incoming_tx);
+                                if process_log(
+                                    &new_contents,
+                                    &mut incoming_tx,
+                                    &player_list_requests,
+                                    admin_list.as_ref(),
+                                )
+                                .is_err()
+                                {
+                                    info!("Message receiver closed, stopping log watcher");
+                                    break;
+                                }
                             }
                             Err(err) => error!("Could not read new file contents: {err}"),
                         }
@@ -140,12 +405,105 @@ fn start_log_watcher(
         info!("Event stream over");
         Ok(())
     });
-    Ok(handle)
+    Ok((watcher, handle))
+}
+
+/// Log lines carry no id, so one is made up from the time the line was read and a counter
+fn next_message_id(timestamp: DateTime<Utc>) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{count}", timestamp.timestamp_micros())
+}
+
+/// Players on the server's admin list, reloaded when the file changes so changes made in game apply right away
+struct AdminList {
+    path: PathBuf,
+    admins: Vec<String>,
+}
+
+impl AdminList {
+    fn load(path: PathBuf) -> Self {
+        let mut admin_list = Self {
+            path,
+            admins: Vec::new(),
+        };
+        admin_list.reload();
+        admin_list
+    }
+
+    /// Keeps the previous list if the file can't be read, e.g. while it is being written
+    fn reload(&mut self) {
+        let admins = std::fs::read_to_string(&self.path)
+            .context("Could not read admin list")
+            .and_then(|contents| {
+                serde_json::from_str::<Vec<String>>(&contents).context("Invalid admin list")
+            });
+        match admins {
+            Ok(admins) => {
+                debug!("Loaded admin list with {} players", admins.len());
+                self.admins = admins;
+            }
+            Err(err) => error!("{err:#}"),
+        }
+    }
+
+    fn directory(&self) -> &Path {
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    fn is_changed_by(&self, event: &notify::Event) -> bool {
+        !event.kind.is_access()
+            && event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == self.path.file_name())
+    }
+
+    fn is_admin(&self, name: &str) -> bool {
+        self.admins
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Player names can't contain spaces, so the name is always the first word
+fn parse_event(event_type: &str, contents: &str) -> Option<ChannelEvent> {
+    let (user_name, details) = match contents.split_once(' ') {
+        Some((user_name, details)) => (user_name.to_owned(), Some(details.to_owned())),
+        None => (contents.to_owned(), None),
+    };
+
+    match event_type {
+        "JOIN" => Some(ChannelEvent::Join { user_name }),
+        "LEAVE" => Some(ChannelEvent::Leave { user_name }),
+        "DIED" => Some(ChannelEvent::Death {
+            user_name,
+            cause: details,
+        }),
+        "ACHIEVEMENT" => Some(ChannelEvent::Achievement {
+            user_name,
+            achievement: details?,
+        }),
+        "RESEARCH" => Some(ChannelEvent::ResearchFinished {
+            technology: contents.to_owned(),
+        }),
+        _ => None,
+    }
 }
 
-fn process_log(new_contents: &str, incoming_tx: &mut mpsc::Sender<IncomingMessage>) {
+/// Fails when the receiving side of the channel is closed
+fn process_log(
+    new_contents: &str,
+    incoming_tx: &mut mpsc::Sender<IncomingEvent>,
+    player_list_requests: &PlayerListRequests,
+    admin_list: Option<&AdminList>,
+) -> anyhow::Result<()> {
     for line in new_contents.lines() {
         debug!("Read new log line {line}");
+        let timestamp = Utc::now();
         if let Some((event_type, contents)) = line.split_once(' ') {
             match event_type {
                 "CHAT" => {
@@ -153,30 +511,29 @@ fn process_log(new_contents: &str, incoming_tx: &mut mpsc::Sender<IncomingMessag
                         Some((name, text)) => {
                             if name != "<server>" {
                                 let msg = IncomingMessage {
+                                    id: next_message_id(timestamp),
+                                    timestamp,
                                     channel_id: None,
+                                    channel_name: None,
                                     user_id: Some(name.to_owned()),
                                     user_name: Some(name.to_owned()),
-                                    contents: text.to_owned(),
+                                    body: parse_rich_text(text),
                                     user_color: None,
+                                    provenance: None,
+                                    event: None,
+                                    user_is_admin: admin_list.is_some_and(|admin_list| admin_list.is_admin(name)),
                                 };
-                                incoming_tx.blocking_send(msg).unwrap();
+                                incoming_tx.blocking_send(IncomingEvent::Message(Box::new(msg)))?;
                             }
                         }
                         None => error!("Could not process line '{line}', expected a split in chat message contents"),
                     }
                 },
                 "PLAYERLIST" => {
-                    if contents.is_empty() {
-                        let msg = IncomingMessage {
-                            channel_id: None,
-                            user_id: None,
-                            user_name: None,
-                            contents: "No players online".to_owned(),
-                            user_color: None,
-                        };
-                        return incoming_tx.blocking_send(msg).unwrap();
-                    }
-                    let list = contents.split(';')
+                    let txt = if contents.is_empty() {
+                        String::from("No players online")
+                    } else {
+                        let list = contents.split(';')
                             .map(|player| {
                                 let (name, mut surface) = player.split_once(' ').unwrap();
                                 // surface can be Phoebe, nauvis, Nauvis Orbit, "detached nauvis" ...
@@ -201,27 +558,131 @@ fn process_log(new_contents: &str, incoming_tx: &mut mpsc::Sender<IncomingMessag
                             })
                             .collect::<Vec<_>>()
                             .join(", ");
-                    let txt = format!("Online players: {list}");
-                    let msg = IncomingMessage {
-                        channel_id: None,
-                        user_id: None,
-                        user_name: None,
-                        contents: txt,
-                        user_color: None,
+                        format!("Online players: {list}")
                     };
-                    incoming_tx.blocking_send(msg).unwrap();
+
+                    let requests = std::mem::take(&mut *player_list_requests.lock().unwrap());
+                    if requests.is_empty() {
+                        debug!("Got a player list nobody asked for");
+                    }
+                    for request in requests {
+                        // The request could have timed out already
+                        let _ = request.send(txt.clone());
+                    }
                 },
                 _ => {
-                    let msg = IncomingMessage {
-                        user_id: None,
-                        channel_id: None,
-                        user_name: None,
-                        contents: contents.to_owned(),
-                        user_color: None,
+                    let msg = match parse_event(event_type, contents) {
+                        Some(event) => event.into_message(next_message_id(timestamp), timestamp, None, None),
+                        None => IncomingMessage {
+                            id: next_message_id(timestamp),
+                            timestamp,
+                            user_id: None,
+                            channel_id: None,
+                            channel_name: None,
+                            user_name: None,
+                            body: MessageBody::from_text(contents),
+                            user_color: None,
+                            provenance: None,
+                            event: None,
+                            user_is_admin: false,
+                        },
                     };
-                    incoming_tx.blocking_send(msg).unwrap();
+                    incoming_tx.blocking_send(IncomingEvent::Message(Box::new(msg)))?;
                 }
             }
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mention, text};
+
+    #[test]
+    fn parses_nested_tags() {
+        let body = parse_rich_text("a [color=red]b [font=default-bold]c[/font][/color] d");
+        assert_eq!(
+            body.segments,
+            [
+                text("a "),
+                Segment::Formatted {
+                    style: Style::Color("red".to_owned()),
+                    segments: vec![
+                        text("b "),
+                        Segment::Formatted {
+                            style: Style::Bold,
+                            segments: vec![text("c")],
+                        },
+                    ],
+                },
+                text(" d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_other_and_unmatched_tags_as_text() {
+        let body = parse_rich_text("[gps=1,2] [/color] [font=default-large]x");
+        assert_eq!(
+            body.segments,
+            [text("[gps=1,2] [/color] [font=default-large]x")]
+        );
+    }
+
+    #[test]
+    fn closes_unclosed_tags_at_the_end() {
+        let body = parse_rich_text("[color=1,0,0]red");
+        assert_eq!(
+            body.segments,
+            [Segment::Formatted {
+                style: Style::Color("1,0,0".to_owned()),
+                segments: vec![text("red")],
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_mentions() {
+        let body = parse_rich_text("@bob hi [color=red]@alice-2[/color] mail@example.com");
+        assert_eq!(
+            body.segments,
+            [
+                mention(Some("bob"), "bob"),
+                text(" hi "),
+                Segment::Formatted {
+                    style: Style::Color("red".to_owned()),
+                    segments: vec![mention(Some("alice-2"), "alice-2")],
+                },
+                text(" mail@example.com"),
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_rich_text() {
+        let text = "a [color=red]b [font=default-bold]c[/font][/color] https://x.y";
+        assert_eq!(Factorio::render_body(&parse_rich_text(text)), text);
+    }
+
+    #[test]
+    fn admin_list_keeps_last_valid_contents() {
+        let path =
+            std::env::temp_dir().join(format!("supabridge-admins-{}.json", std::process::id()));
+        std::fs::write(&path, r#"["Alice"]"#).unwrap();
+        let mut admin_list = AdminList::load(path.clone());
+        assert!(admin_list.is_admin("alice"));
+        assert!(!admin_list.is_admin("bob"));
+
+        std::fs::write(&path, r#"["bob"#).unwrap();
+        admin_list.reload();
+        assert!(admin_list.is_admin("alice"));
+
+        std::fs::write(&path, r#"["bob"]"#).unwrap();
+        admin_list.reload();
+        assert!(!admin_list.is_admin("alice"));
+        assert!(admin_list.is_admin("bob"));
+        std::fs::remove_file(path).unwrap();
+    }
 }
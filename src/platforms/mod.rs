@@ -4,13 +4,36 @@ mod twitch;
 pub use factorio::Factorio;
 pub use twitch::Twitch;
 
-use crate::{config::Config, DbPool, IncomingMessage, OutgoingMessage};
+use crate::{
+    commands::{CommandRequest, CommandSpec},
+    config::Config,
+    health::HealthCheck,
+    message_body::MessageBody,
+    DbPool, IncomingEvent, OutgoingDeletion, OutgoingMessage,
+};
+use anyhow::anyhow;
 use axum::Router;
 use futures::Future;
 use serde::de::DeserializeOwned;
+use std::fmt;
 use tokio::sync::mpsc;
 
-pub trait ChatPlatform: 'static + Sized + Send {
+/// Context for send errors that retrying will not fix, e.g. a message the platform rejected.
+/// Such messages are dropped instead of being kept in the outbox.
+#[derive(Debug)]
+pub struct PermanentError;
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The message cannot be delivered")
+    }
+}
+
+pub fn is_permanent(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<PermanentError>().is_some()
+}
+
+pub trait ChatPlatform: 'static + Sized + Send + Sync {
     const NAME: &'static str;
     type Config: DeserializeOwned;
 
@@ -25,13 +48,83 @@ pub trait ChatPlatform: 'static + Sized + Send {
         Router::new()
     }
 
+    /// Runs the platform's background work, gets restarted if it fails
     fn run(
-        self,
-        incoming_message_tx: mpsc::Sender<IncomingMessage>,
-        outgoing_message_rx: mpsc::Receiver<OutgoingMessage>,
+        &self,
+        incoming_message_tx: mpsc::Sender<IncomingEvent>,
     ) -> impl Future<Output = anyhow::Result<()>> + Send;
 
+    /// Returns the id of the sent message, if the platform has message ids
+    fn send_msg(
+        &self,
+        outgoing_msg: OutgoingMessage,
+    ) -> impl Future<Output = anyhow::Result<Option<String>>> + Send;
+
+    /// Only called on platforms that support deletion
+    fn delete_msg(
+        &self,
+        _deletion: OutgoingDeletion,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Err(anyhow!("Deleting messages is not supported")) }
+    }
+
+    /// Called when the mirrored channels change after a config reload
+    fn update_channels(
+        &self,
+        _channel_ids: Vec<String>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Called once all queued messages have been sent, right before the bridge exits
+    fn shutdown(
+        &self,
+        _offline_notice: Option<&str>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Runs one of the platform's commands, returning the reply for the requesting channel
+    fn run_command(
+        &self,
+        _request: CommandRequest,
+    ) -> impl Future<Output = anyhow::Result<String>> + Send {
+        async { Err(anyhow!("The platform has no commands")) }
+    }
+
+    /// Platform specific checks reported by `/healthz` and `/readyz`, e.g. whether it is connected
+    fn health_checks(&self) -> impl Future<Output = Vec<HealthCheck>> + Send {
+        async { Vec::new() }
+    }
+
+    /// Commands answered by this platform, they can be used from any channel bridged with it
+    fn commands() -> &'static [CommandSpec] {
+        &[]
+    }
+
+    /// Id of the account the bridge itself uses on the platform, if it has one
+    fn bot_user_id(&self) -> Option<String> {
+        None
+    }
+
     fn supports_zws() -> bool {
         true
     }
+
+    fn supports_deletion() -> bool {
+        false
+    }
+
+    /// Renders message bodies in the platform's syntax, used for the `{contents}` placeholder
+    fn render_body(body: &MessageBody) -> String {
+        body.plain_text()
+    }
+
+    fn default_template() -> &'static str {
+        "[{platform}] {name}: {contents}"
+    }
+
+    fn default_system_template() -> &'static str {
+        "[{platform}] {contents}"
+    }
 }
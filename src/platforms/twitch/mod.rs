@@ -1,28 +1,59 @@
 mod web;
 
-use super::ChatPlatform;
-use crate::{DbPool, IncomingMessage, OutgoingMessage};
-use anyhow::Context;
+use super::{ChatPlatform, PermanentError};
+use crate::{
+    events::ChannelEvent,
+    health::HealthCheck,
+    message_body::{MessageBody, Segment},
+    metrics, DbPool, DeletionTarget, IncomingEvent, IncomingMessage, MessageDeletion,
+    OutgoingDeletion, OutgoingMessage, Provenance,
+};
+use anyhow::{anyhow, bail, Context};
 use axum::routing::{get, post};
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use reqwest::StatusCode;
 use serde::Deserialize;
 use std::{
-    collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
-    time::Duration,
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use twitch_api::{
-    eventsub::{self, channel::ChannelChatMessageV1Payload, EventSubscription, Status},
+    eventsub::{
+        self,
+        channel::chat::Fragment,
+        channel::{
+            ChannelChatClearUserMessagesV1, ChannelChatClearUserMessagesV1Payload,
+            ChannelChatMessageDeleteV1, ChannelChatMessageDeleteV1Payload, ChannelChatMessageV1,
+            ChannelChatMessageV1Payload, ChannelRaidV1, ChannelSubscribeV1,
+        },
+        stream::{StreamOfflineV1, StreamOnlineV1},
+        EventSubscription, EventType, Status,
+    },
     helix::{self, ClientRequestError, HelixRequestPostError},
     twitch_oauth2::AppAccessToken,
     types::MsgId,
 };
-use twitch_oauth2::{CsrfToken, Scope, UserTokenBuilder};
+use twitch_oauth2::{
+    tokens::errors::{RefreshTokenError, RetrieveTokenError, ValidationError},
+    AccessToken, ClientId, ClientSecret, CsrfToken, RefreshToken, RequestParseError, Scope,
+    TwitchToken, UserToken, UserTokenBuilder,
+};
 
 type HelixClient = twitch_api::HelixClient<'static, reqwest::Client>;
+/// Messages sent by the bridge with the time they were sent, until their echo arrives through EventSub
+type SentMessages = HashMap<MsgId, (Instant, Provenance)>;
+
+const EVENTSUB_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Tokens are refreshed when they expire in less than this
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// Has to be shorter than the margin so tokens are renewed before they expire
+const TOKEN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Sent messages whose echo did not arrive within this are forgotten, e.g. when the chat subscription is missing
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
@@ -36,12 +67,18 @@ pub struct Config {
 pub struct Twitch {
     helix: HelixClient,
     bot_user: helix::users::User,
-    app_token: AppAccessToken,
+    /// Renewed in the background, see [`Twitch::refresh_tokens`]
+    app_token: Arc<RwLock<AppAccessToken>>,
     base_url: String,
     config: Config,
+    db: DbPool,
     csrf_tokens: Arc<Mutex<HashMap<CsrfToken, UserTokenBuilder>>>,
-    channel_ids: Vec<String>,
-    recently_sent_messages: Arc<tokio::sync::Mutex<HashSet<MsgId>>>,
+    channel_ids: Arc<Mutex<Vec<String>>>,
+    recently_sent_messages: Arc<tokio::sync::Mutex<SentMessages>>,
+    /// Tokens of linked users for sending messages as them, by user id
+    user_tokens: Arc<tokio::sync::Mutex<HashMap<String, UserToken>>>,
+    /// Channels without a chat message subscription after the last EventSub check, `None` before the first check
+    unsubscribed_channels: Arc<Mutex<Option<Vec<String>>>>,
 }
 
 impl ChatPlatform for Twitch {
@@ -52,7 +89,7 @@ impl ChatPlatform for Twitch {
         config: Self::Config,
         global_config: &crate::Config,
         channel_ids: Vec<String>,
-        _db: &DbPool,
+        db: &DbPool,
     ) -> anyhow::Result<Self> {
         let helix = HelixClient::new();
 
@@ -69,35 +106,171 @@ impl ChatPlatform for Twitch {
             .await?
             .context("The bot's user does not exist")?;
 
-        Ok(Self {
-            app_token,
+        let twitch = Self {
+            app_token: Arc::new(RwLock::new(app_token)),
             helix,
             bot_user,
             config,
+            db: db.clone(),
             base_url: global_config.general.base_url.clone(),
             csrf_tokens: Arc::default(),
-            channel_ids,
+            channel_ids: Arc::new(Mutex::new(channel_ids)),
             recently_sent_messages: Arc::default(),
-        })
+            user_tokens: Arc::default(),
+            unsubscribed_channels: Arc::default(),
+        };
+
+        // Runs separately from `run`, so the tokens are still renewed while the platform is restarting
+        let refreshed = twitch.clone();
+        tokio::spawn(async move {
+            loop {
+                refreshed.refresh_tokens().await;
+                tokio::time::sleep(TOKEN_CHECK_INTERVAL).await;
+            }
+        });
+        Ok(twitch)
     }
 
-    async fn run(
-        self,
-        _message_tx: mpsc::Sender<IncomingMessage>,
-        mut outgoing_message_rx: mpsc::Receiver<OutgoingMessage>,
-    ) -> anyhow::Result<()> {
+    async fn run(&self, _message_tx: mpsc::Sender<IncomingEvent>) -> anyhow::Result<()> {
+        // Messages are received through the EventSub webhook, this only makes sure the subscriptions stay active
+        loop {
+            // The background refresh might not have caught up yet, e.g. after the machine was suspended
+            self.refresh_app_token()
+                .await
+                .context("Could not renew the app access token")?;
+            self.setup_eventsub()
+                .await
+                .context("Could not set up EventSub")?;
+            tokio::time::sleep(EVENTSUB_CHECK_INTERVAL).await;
+        }
+    }
+
+    async fn send_msg(&self, outgoing_msg: OutgoingMessage) -> anyhow::Result<Option<String>> {
+        let channel_id = outgoing_msg
+            .target_channel_id
+            .ok_or_else(|| anyhow!("Cannot send without a channel").context(PermanentError))?;
+
+        // Linked users send the message under their own name, so it does not need any formatting
+        let mut sent_as_user = None;
+        if let Some(sender_id) = outgoing_msg.sender_user_id.as_deref() {
+            if let Some(token) = self.user_token(sender_id).await {
+                let body = helix::chat::SendChatMessageBody::new(
+                    channel_id.as_str(),
+                    sender_id,
+                    outgoing_msg.unformatted_content.as_str(),
+                );
+                match self.post_chat_message(body, &token).await {
+                    Ok(response) => sent_as_user = Some(response),
+                    Err(err) => {
+                        warn!("Could not send message as user {sender_id}, sending it as the bot: {err:#}");
+                        self.user_tokens.lock().await.remove(sender_id);
+                        if is_unauthorized(&err) {
+                            if let Err(err) = self.mark_needs_reauth(sender_id).await {
+                                error!("{err:#}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let response = match sent_as_user {
+            Some(response) => response,
+            None => {
+                let body = helix::chat::SendChatMessageBody::new(
+                    channel_id.as_str(),
+                    self.bot_user.id.as_str(),
+                    outgoing_msg.content.as_str(),
+                );
+                self.post_chat_message(body, &self.app_token()).await?
+            }
+        };
+        if !response.is_sent {
+            error!("Message did not get sent: {:?}", response.drop_reason);
+        }
+
+        let msg_id = response.message_id;
+        if let Some(msg_id) = &msg_id {
+            let mut recently_sent = self.recently_sent_messages.lock().await;
+            recently_sent.retain(|_, (sent_at, _)| sent_at.elapsed() < ECHO_TIMEOUT);
+            recently_sent.insert(msg_id.clone(), (Instant::now(), outgoing_msg.provenance));
+        }
+        Ok(msg_id.map(|msg_id| msg_id.to_string()))
+    }
+
+    async fn delete_msg(&self, deletion: OutgoingDeletion) -> anyhow::Result<()> {
+        let channel_id = deletion
+            .target_channel_id
+            .context("Cannot delete without a channel")?;
+        let token = self.bot_user_token().await?;
+
+        let req = helix::moderation::DeleteChatMessagesRequest::new(
+            channel_id.as_str(),
+            self.bot_user.id.as_str(),
+        )
+        .message_id(deletion.message_id.as_str());
+        self.helix.req_delete(req, &token).await?;
+        Ok(())
+    }
+
+    async fn update_channels(&self, channel_ids: Vec<String>) -> anyhow::Result<()> {
+        *self.channel_ids.lock().unwrap() = channel_ids;
         self.setup_eventsub()
             .await
-            .context("Could not set up EventSub")?;
+            .context("Could not update EventSub subscriptions")
+    }
+
+    async fn shutdown(&self, offline_notice: Option<&str>) -> anyhow::Result<()> {
+        let Some(notice) = offline_notice else {
+            return Ok(());
+        };
 
-        while let Some(outgoing_msg) = outgoing_message_rx.recv().await {
-            if let Err(err) = self.send_msg(outgoing_msg).await {
-                error!("Could not send message: {err:#}");
+        let channel_ids = self.channel_ids.lock().unwrap().clone();
+        let app_token = self.app_token();
+        for channel_id in channel_ids {
+            let req = helix::chat::SendChatMessageRequest::new();
+            let body = helix::chat::SendChatMessageBody::new(
+                channel_id.as_str(),
+                self.bot_user.id.as_str(),
+                notice,
+            );
+            if let Err(err) = self.helix.req_post(req, body, &app_token).await {
+                error!("Could not send offline notice to channel {channel_id}: {err}");
             }
         }
         Ok(())
     }
 
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        let app_token = if self.app_token().is_elapsed() {
+            Err("The app access token expired".to_owned())
+        } else {
+            Ok(())
+        };
+        let logins = match self.logins_needing_reauth().await {
+            Ok(user_ids) if user_ids.is_empty() => Ok(()),
+            Ok(user_ids) => Err(format!(
+                "The logins of {} have to be renewed at /platform/twitch/auth",
+                user_ids.join(", ")
+            )),
+            Err(err) => Err(format!("{err:#}")),
+        };
+        let eventsub = match self.unsubscribed_channels.lock().unwrap().as_deref() {
+            None => Err("Subscriptions were not set up yet".to_owned()),
+            Some([]) => Ok(()),
+            Some(channel_ids) => Err(format!(
+                "No chat message subscription for channels {}",
+                channel_ids.join(", ")
+            )),
+        };
+
+        vec![
+            HealthCheck::new("app_token", app_token),
+            HealthCheck::new("logins", logins),
+            HealthCheck::new("eventsub", eventsub),
+        ]
+    }
+
     fn api_routes(&mut self) -> axum::Router {
         axum::Router::new()
             .route("/eventsub", post(web::eventsub_callback))
@@ -105,146 +278,570 @@ impl ChatPlatform for Twitch {
             .route("/auth/redirect", get(web::auth_redirect))
             .with_state(Arc::new(self.clone()))
     }
+
+    fn bot_user_id(&self) -> Option<String> {
+        Some(self.bot_user.id.to_string())
+    }
+
+    fn supports_deletion() -> bool {
+        true
+    }
 }
 
 impl Twitch {
     async fn handle_message(
         &self,
         msg: ChannelChatMessageV1Payload,
-        message_tx: mpsc::Sender<IncomingMessage>,
+        timestamp: DateTime<Utc>,
+        message_tx: mpsc::Sender<IncomingEvent>,
     ) -> anyhow::Result<()> {
         // The message was sent by the bridge itself
-        if self
+        let provenance = self
             .recently_sent_messages
             .lock()
             .await
             .remove(&msg.message_id)
-        {
-            return Ok(());
-        }
+            .map(|(_, provenance)| provenance);
+
+        let user_is_admin = msg
+            .badges
+            .iter()
+            .any(|badge| matches!(badge.set_id.as_str(), "broadcaster" | "moderator"));
 
         let color = msg.color.as_str().trim_start_matches('#').to_owned();
         let user_color = if color.is_empty() { None } else { Some(color) };
 
         message_tx
-            .send(IncomingMessage {
+            .send(IncomingEvent::Message(Box::new(IncomingMessage {
+                id: msg.message_id.to_string(),
+                timestamp,
                 channel_id: Some(msg.broadcaster_user_id.to_string()),
+                channel_name: Some(msg.broadcaster_user_name.to_string()),
                 user_id: Some(msg.chatter_user_id.to_string()),
                 user_name: Some(msg.chatter_user_name.to_string()),
-                contents: msg.message.text,
+                body: message_body(msg.message.fragments),
                 user_color,
-            })
+                provenance,
+                event: None,
+                user_is_admin,
+            })))
             .await?;
 
         Ok(())
     }
 
-    async fn send_msg(&self, outgoing_msg: OutgoingMessage) -> anyhow::Result<()> {
-        let mut recently_sent = self.recently_sent_messages.lock().await;
+    async fn handle_channel_event(
+        &self,
+        event: ChannelEvent,
+        event_id: String,
+        timestamp: DateTime<Utc>,
+        channel_id: String,
+        channel_name: String,
+        message_tx: mpsc::Sender<IncomingEvent>,
+    ) -> anyhow::Result<()> {
+        let msg = event.into_message(event_id, timestamp, Some(channel_id), Some(channel_name));
+        message_tx
+            .send(IncomingEvent::Message(Box::new(msg)))
+            .await?;
+        Ok(())
+    }
 
-        let channel_id = outgoing_msg
-            .target_channel_id
-            .context("Cannot send without a channel")?;
+    async fn handle_message_delete(
+        &self,
+        payload: ChannelChatMessageDeleteV1Payload,
+        message_tx: mpsc::Sender<IncomingEvent>,
+    ) -> anyhow::Result<()> {
+        message_tx
+            .send(IncomingEvent::Deletion(MessageDeletion {
+                channel_id: Some(payload.broadcaster_user_id.to_string()),
+                target: DeletionTarget::Message {
+                    message_id: payload.message_id.to_string(),
+                },
+            }))
+            .await?;
+        Ok(())
+    }
 
-        let sender_id = outgoing_msg
-            .sender_user_id
-            .as_deref()
-            .unwrap_or(self.bot_user.id.as_str());
+    async fn handle_clear_user_messages(
+        &self,
+        payload: ChannelChatClearUserMessagesV1Payload,
+        message_tx: mpsc::Sender<IncomingEvent>,
+    ) -> anyhow::Result<()> {
+        message_tx
+            .send(IncomingEvent::Deletion(MessageDeletion {
+                channel_id: Some(payload.broadcaster_user_id.to_string()),
+                target: DeletionTarget::User {
+                    user_id: payload.target_user_id.to_string(),
+                },
+            }))
+            .await?;
+        Ok(())
+    }
 
+    async fn post_chat_message<T: TwitchToken + Send + Sync>(
+        &self,
+        body: helix::chat::SendChatMessageBody<'_>,
+        token: &T,
+    ) -> anyhow::Result<helix::chat::SendChatMessageResponse> {
         let req = helix::chat::SendChatMessageRequest::new();
-        let body =
-            helix::chat::SendChatMessageBody::new(channel_id, sender_id, outgoing_msg.content);
-        match self
-            .helix
-            .req_post(req.clone(), body.clone(), &self.app_token)
-            .await
-        {
-            Ok(response) => {
-                if !response.data.is_sent {
-                    error!("Message did not get sent: {:?}", response.data.drop_reason);
+        match self.helix.req_post(req.clone(), body.clone(), token).await {
+            Ok(response) => Ok(response.data),
+            Err(ClientRequestError::HelixRequestPostError(HelixRequestPostError::Error {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                ..
+            })) => {
+                metrics::TWITCH_RATE_LIMIT_RETRIES.inc(&[]);
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok(self.helix.req_post(req, body, token).await?.data)
+            }
+            // e.g. the bot is banned from the channel or the message is too long, sending it again would not help.
+            // Expired tokens are renewed in the background, so those are retried.
+            Err(ClientRequestError::HelixRequestPostError(HelixRequestPostError::Error {
+                status,
+                message,
+                ..
+            })) if status.is_client_error() && status != StatusCode::UNAUTHORIZED => {
+                Err(anyhow!("Twitch rejected the message ({status}): {message}")
+                    .context(PermanentError))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Token of a linked user that logged in with /platform/twitch/auth?mode=sender, refreshed when needed.
+    /// `None` when the user did not log in or the login was revoked, their messages are sent by the bot then.
+    async fn user_token(&self, user_id: &str) -> Option<UserToken> {
+        let mut user_tokens = self.user_tokens.lock().await;
+
+        if let Some(token) = user_tokens.get_mut(user_id) {
+            if token.expires_in() > TOKEN_REFRESH_MARGIN {
+                return Some(token.clone());
+            }
+            return match token.refresh_token(self.helix.get_client()).await {
+                Ok(()) => {
+                    if let Err(err) = self.save_user_token(token).await {
+                        error!("{err:#}");
+                    }
+                    Some(token.clone())
                 }
-                if let Some(msg_id) = response.data.message_id {
-                    recently_sent.insert(msg_id);
+                Err(err) => {
+                    warn!("Could not refresh the token of user {user_id}: {err}");
+                    user_tokens.remove(user_id);
+                    None
                 }
+            };
+        }
+
+        match self.load_user_token(user_id).await {
+            Ok(Some(token)) => {
+                user_tokens.insert(user_id.to_owned(), token.clone());
+                Some(token)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!("Could not load the token of user {user_id}: {err:#}");
+                None
             }
-            Err(err) => match err {
-                ClientRequestError::HelixRequestPostError(HelixRequestPostError::Error {
-                    status: StatusCode::TOO_MANY_REQUESTS,
-                    ..
-                }) => {
-                    tokio::time::sleep(Duration::from_millis(500)).await;
-                    self.helix.req_post(req, body, &self.app_token).await?;
+        }
+    }
+
+    async fn load_user_token(&self, user_id: &str) -> anyhow::Result<Option<UserToken>> {
+        let login = sqlx::query!(
+            "SELECT access_token, refresh_token, scopes FROM twitch_login
+            WHERE user_id = ? AND NOT needs_reauth",
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let Some(login) = login else {
+            return Ok(None);
+        };
+        let write_scope = Scope::UserWriteChat.to_string();
+        if !login.scopes.split(' ').any(|scope| scope == write_scope) {
+            return Ok(None);
+        }
+
+        let token = match self
+            .existing_user_token(login.access_token, login.refresh_token)
+            .await
+        {
+            Ok(token) => token,
+            Err(err) => {
+                if login_rejected(&err) {
+                    self.mark_needs_reauth(user_id).await?;
                 }
-                other => return Err(other.into()),
-            },
+                return Err(err).context("The login is not valid");
+            }
+        };
+
+        // The token might have been refreshed
+        self.save_user_token(&token).await?;
+        Ok(Some(token))
+    }
+
+    async fn existing_user_token(
+        &self,
+        access_token: String,
+        refresh_token: String,
+    ) -> Result<UserToken, RetrieveTokenError<reqwest::Error>> {
+        UserToken::from_existing_or_refresh_token(
+            self.helix.get_client(),
+            AccessToken::new(access_token),
+            RefreshToken::new(refresh_token),
+            ClientId::new(self.config.client_id.clone()),
+            ClientSecret::new(self.config.client_secret.clone()),
+        )
+        .await
+    }
+
+    async fn save_user_token(&self, token: &UserToken) -> anyhow::Result<()> {
+        let user_id = token.user_id.as_str();
+        let access_token = token.access_token.as_str();
+        let refresh_token = token.refresh_token.as_ref().map(|token| token.as_str());
+        let expires_at = Utc::now().timestamp() + token.expires_in().as_secs() as i64;
+        sqlx::query!(
+            "UPDATE twitch_login
+            SET access_token = ?, refresh_token = COALESCE(?, refresh_token), expires_at = ?,
+                needs_reauth = FALSE
+            WHERE user_id = ?",
+            access_token,
+            refresh_token,
+            expires_at,
+            user_id,
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not save refreshed user token")?;
+        Ok(())
+    }
+
+    async fn mark_needs_reauth(&self, user_id: &str) -> anyhow::Result<()> {
+        warn!("Twitch rejected the login of user {user_id}, they have to log in again");
+        sqlx::query!(
+            "UPDATE twitch_login SET needs_reauth = TRUE WHERE user_id = ?",
+            user_id
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not mark login as expired")?;
+        Ok(())
+    }
+
+    /// Logins of the bot and the bridged channels that Twitch rejected
+    async fn logins_needing_reauth(&self) -> anyhow::Result<Vec<String>> {
+        let user_ids = sqlx::query_scalar!(
+            r#"SELECT user_id as "user_id!" FROM twitch_login WHERE needs_reauth"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let channel_ids = self.channel_ids.lock().unwrap().clone();
+        Ok(user_ids
+            .into_iter()
+            .filter(|user_id| {
+                *user_id == self.bot_user.id.as_str() || channel_ids.contains(user_id)
+            })
+            .collect())
+    }
+
+    fn app_token(&self) -> AppAccessToken {
+        self.app_token.read().unwrap().clone()
+    }
+
+    /// Gets a new app token when the current one is about to expire
+    async fn refresh_app_token(&self) -> anyhow::Result<()> {
+        let mut app_token = self.app_token();
+        if app_token.expires_in() >= TOKEN_REFRESH_MARGIN {
+            return Ok(());
         }
+        app_token.refresh_token(self.helix.get_client()).await?;
+        info!("Renewed the app access token");
+        *self.app_token.write().unwrap() = app_token;
         Ok(())
     }
 
+    /// Renews the app token and the stored logins that are about to expire.
+    /// Logins with a refresh token that Twitch rejects are marked as needing a new login.
+    async fn refresh_tokens(&self) {
+        if let Err(err) = self.refresh_app_token().await {
+            error!("Could not renew the app access token: {err:#}");
+        }
+
+        let refresh_before = Utc::now().timestamp() + TOKEN_REFRESH_MARGIN.as_secs() as i64;
+        let logins = sqlx::query!(
+            r#"SELECT user_id as "user_id!", access_token, refresh_token FROM twitch_login
+            WHERE NOT needs_reauth AND (expires_at IS NULL OR expires_at < ?)"#,
+            refresh_before
+        )
+        .fetch_all(&self.db)
+        .await;
+        let logins = match logins {
+            Ok(logins) => logins,
+            Err(err) => {
+                error!("Could not load Twitch logins: {err}");
+                return;
+            }
+        };
+
+        for login in logins {
+            let user_id = login.user_id;
+            // Tokens that are still valid are not refreshed by `from_existing_or_refresh_token`
+            let renewed = match self
+                .existing_user_token(login.access_token, login.refresh_token)
+                .await
+            {
+                Ok(mut token) if token.expires_in() < TOKEN_REFRESH_MARGIN => token
+                    .refresh_token(self.helix.get_client())
+                    .await
+                    .map(|()| token)
+                    .map_err(RetrieveTokenError::from),
+                result => result,
+            };
+
+            let result = match renewed {
+                Ok(token) => {
+                    debug!("Renewed the login of user {user_id}");
+                    if let Some(cached) = self.user_tokens.lock().await.get_mut(&user_id) {
+                        *cached = token.clone();
+                    }
+                    self.save_user_token(&token).await
+                }
+                Err(err) if login_rejected(&err) => {
+                    self.user_tokens.lock().await.remove(&user_id);
+                    self.mark_needs_reauth(&user_id).await
+                }
+                Err(err) => {
+                    warn!("Could not renew the login of user {user_id}, retrying later: {err}");
+                    Ok(())
+                }
+            };
+            if let Err(err) = result {
+                error!("{err:#}");
+            }
+        }
+    }
+
+    /// The bot's own login from the `twitch_login` table, needed for moderator actions
+    async fn bot_user_token(&self) -> anyhow::Result<UserToken> {
+        let user_id = self.bot_user.id.as_str();
+        let login = sqlx::query!(
+            "SELECT access_token, refresh_token, needs_reauth FROM twitch_login WHERE user_id = ?",
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .context(
+            "The bot is not logged in, authenticate it with /platform/twitch/auth?mode=user",
+        )?;
+        if login.needs_reauth {
+            bail!(
+                "The bot's login expired, authenticate it again with /platform/twitch/auth?mode=user"
+            );
+        }
+
+        UserToken::from_existing(
+            self.helix.get_client(),
+            AccessToken::new(login.access_token),
+            RefreshToken::new(login.refresh_token),
+            ClientSecret::new(self.config.client_secret.clone()),
+        )
+        .await
+        .context("The bot's login is not valid")
+    }
+
     async fn setup_eventsub(&self) -> anyhow::Result<()> {
         info!("Updating EventSub subscriptions");
-        let mut channel_ids = self.channel_ids.clone();
+        let bridged_channel_ids = self.channel_ids.lock().unwrap().clone();
+        let mut active_subscriptions: Vec<(EventType, String)> = Vec::new();
+        let mut stale_subscriptions = Vec::new();
         let callback_url = format!("{}/platform/twitch/eventsub", self.base_url);
 
-        let mut current_subs = self.helix.get_eventsub_subscriptions(
-            Status::Enabled,
-            Some(eventsub::channel::ChannelChatMessageV1::EVENT_TYPE),
-            None,
-            &self.app_token,
-        );
+        let app_token = self.app_token();
+        let mut current_subs =
+            self.helix
+                .get_eventsub_subscriptions(Status::Enabled, None, None, &app_token);
 
         while let Some(current_sub) = current_subs.next().await.transpose()? {
             for sub in current_sub.subscriptions {
-                if sub
+                if !sub
                     .transport
                     .try_into_webhook()
                     .is_ok_and(|webhook| webhook.callback == callback_url)
                 {
-                    let condition: eventsub::channel::ChannelChatMessageV1 =
-                        serde_json::from_value(sub.condition)
-                            .context("Invalid Twitch EventSub response")?;
-
-                    if let Some(pos) = channel_ids
-                        .iter()
-                        .position(|id| id == condition.broadcaster_user_id.as_str())
-                    {
-                        debug!(
-                            "Channel {} already has an active EventSub subscription",
-                            condition.broadcaster_user_id
+                    continue;
+                }
+
+                // All subscription types used by the bridge are per channel, others are left alone
+                let condition = match serde_json::from_value::<ChannelCondition>(sub.condition) {
+                    Ok(condition) => condition,
+                    Err(err) => {
+                        warn!(
+                            "Skipping {:?} subscription {} with an unexpected condition: {err}",
+                            sub.type_, sub.id
                         );
-                        channel_ids.remove(pos);
+                        continue;
                     }
+                };
+
+                if bridged_channel_ids.contains(&condition.broadcaster_user_id) {
+                    debug!(
+                        "Channel {} already has an active {:?} subscription",
+                        condition.broadcaster_user_id, sub.type_
+                    );
+                    active_subscriptions.push((sub.type_, condition.broadcaster_user_id));
+                } else {
+                    info!(
+                        "Channel {} is no longer bridged, removing its {:?} subscription",
+                        condition.broadcaster_user_id, sub.type_
+                    );
+                    stale_subscriptions.push(sub.id);
                 }
             }
         }
 
-        let transport =
-            eventsub::Transport::webhook(callback_url, self.config.eventsub_secret.clone());
-        for channel_id in channel_ids {
-            match self
+        // A subscription that can't be removed only delivers events that get ignored, it must not block the new ones
+        for subscription_id in stale_subscriptions {
+            if let Err(err) = self
                 .helix
-                .create_eventsub_subscription(
-                    eventsub::channel::ChannelChatMessageV1::new(
-                        channel_id.clone(),
-                        self.bot_user.id.clone(),
-                    ),
-                    transport.clone(),
-                    &self.app_token,
-                )
+                .delete_eventsub_subscription(subscription_id.clone(), &app_token)
                 .await
             {
-                Ok(response) => {
-                    info!(
-                        "Established subscription to channel {}",
-                        response.condition.broadcaster_user_id
-                    );
-                }
-                Err(err) => {
-                    error!("Could not establish subscription to channel {channel_id}: {err}",);
+                warn!("Could not remove EventSub subscription {subscription_id}: {err}");
+            }
+        }
+
+        let transport =
+            eventsub::Transport::webhook(callback_url, self.config.eventsub_secret.clone());
+        let mut unsubscribed_channels = Vec::new();
+        for channel_id in bridged_channel_ids {
+            let is_subscribed = |event_type: EventType| {
+                active_subscriptions
+                    .iter()
+                    .any(|active| active.0 == event_type && active.1 == channel_id)
+            };
+
+            if !is_subscribed(ChannelChatMessageV1::EVENT_TYPE) {
+                let subscription =
+                    ChannelChatMessageV1::new(channel_id.clone(), self.bot_user.id.clone());
+                if !self.subscribe(subscription, &channel_id, &transport).await {
+                    unsubscribed_channels.push(channel_id.clone());
                 }
             }
+            if !is_subscribed(ChannelChatMessageDeleteV1::EVENT_TYPE) {
+                let subscription =
+                    ChannelChatMessageDeleteV1::new(channel_id.clone(), self.bot_user.id.clone());
+                self.subscribe(subscription, &channel_id, &transport).await;
+            }
+            if !is_subscribed(ChannelChatClearUserMessagesV1::EVENT_TYPE) {
+                let subscription = ChannelChatClearUserMessagesV1::new(
+                    channel_id.clone(),
+                    self.bot_user.id.clone(),
+                );
+                self.subscribe(subscription, &channel_id, &transport).await;
+            }
+            if !is_subscribed(StreamOnlineV1::EVENT_TYPE) {
+                let subscription = StreamOnlineV1::broadcaster_user_id(channel_id.clone());
+                self.subscribe(subscription, &channel_id, &transport).await;
+            }
+            if !is_subscribed(StreamOfflineV1::EVENT_TYPE) {
+                let subscription = StreamOfflineV1::broadcaster_user_id(channel_id.clone());
+                self.subscribe(subscription, &channel_id, &transport).await;
+            }
+            if !is_subscribed(ChannelRaidV1::EVENT_TYPE) {
+                let subscription = ChannelRaidV1::to_broadcaster_user_id(channel_id.clone());
+                self.subscribe(subscription, &channel_id, &transport).await;
+            }
+            // Only works after the broadcaster authenticated with /platform/twitch/auth?mode=channel
+            if !is_subscribed(ChannelSubscribeV1::EVENT_TYPE) {
+                let subscription = ChannelSubscribeV1::broadcaster_user_id(channel_id.clone());
+                self.subscribe(subscription, &channel_id, &transport).await;
+            }
         }
 
+        *self.unsubscribed_channels.lock().unwrap() = Some(unsubscribed_channels);
         Ok(())
     }
+
+    /// Returns whether the subscription was created
+    async fn subscribe<E: EventSubscription + Send>(
+        &self,
+        subscription: E,
+        channel_id: &str,
+        transport: &eventsub::Transport,
+    ) -> bool {
+        match self
+            .helix
+            .create_eventsub_subscription(subscription, transport.clone(), &self.app_token())
+            .await
+        {
+            Ok(_) => {
+                info!(
+                    "Established {:?} subscription to channel {channel_id}",
+                    E::EVENT_TYPE
+                );
+                true
+            }
+            Err(err) => {
+                error!(
+                    "Could not establish {:?} subscription to channel {channel_id}: {err}",
+                    E::EVENT_TYPE
+                );
+                false
+            }
+        }
+    }
+}
+
+fn message_body(fragments: Vec<Fragment>) -> MessageBody {
+    let mut body = MessageBody::default();
+    for fragment in fragments {
+        match fragment {
+            Fragment::Text { text } => body.push_text(&text),
+            Fragment::Emote { text, .. } | Fragment::Cheermote { text, .. } => {
+                body.push(Segment::Emote { name: text });
+            }
+            Fragment::Mention { mention, .. } => body.push(Segment::Mention {
+                user_id: Some(mention.user_id.to_string()),
+                name: mention.user_name.to_string(),
+            }),
+        }
+    }
+    body
+}
+
+/// Whether Twitch rejected the token a Helix request was made with
+fn is_unauthorized(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ClientRequestError<reqwest::Error>>(),
+        Some(ClientRequestError::HelixRequestPostError(
+            HelixRequestPostError::Error {
+                status: StatusCode::UNAUTHORIZED,
+                ..
+            }
+        ))
+    )
+}
+
+/// Whether Twitch explicitly rejected the token or refresh token.
+/// Anything else, e.g. an outage or an unexpected response, is retried later.
+fn login_rejected(err: &RetrieveTokenError<reqwest::Error>) -> bool {
+    let parse_err = match err {
+        RetrieveTokenError::ValidationError(ValidationError::NotAuthorized) => return true,
+        RetrieveTokenError::ValidationError(ValidationError::RequestParseError(parse_err))
+        | RetrieveTokenError::RefreshTokenError(RefreshTokenError::RequestParseError(parse_err)) => {
+            parse_err
+        }
+        _ => return false,
+    };
+    matches!(
+        parse_err,
+        RequestParseError::TwitchError(response)
+            if matches!(response.status, StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED)
+    )
+}
+
+#[derive(Deserialize)]
+struct ChannelCondition {
+    // Raids are subscribed to with the receiving channel
+    #[serde(alias = "to_broadcaster_user_id")]
+    broadcaster_user_id: String,
 }
@@ -1,22 +1,27 @@
-use crate::{DbPool, IncomingMessage};
+use crate::{events::ChannelEvent, DbPool, IncomingEvent};
 use axum::{
     extract::{Query, State},
     http::{self, StatusCode},
-    response::Redirect,
+    response::{IntoResponse, Redirect, Response},
     Extension,
 };
+use chrono::{DateTime, Utc};
+use futures::Future;
 use http_body_util::BodyExt;
 use serde::Deserialize;
 use std::{fmt, sync::Arc};
 use tokio::sync::mpsc;
 use tracing::{error, info, warn};
-use twitch_api::eventsub;
-use twitch_oauth2::{CsrfToken, Scope, UserTokenBuilder};
+use twitch_api::{
+    eventsub::{self, EventSubscription},
+    types::SubscriptionTier,
+};
+use twitch_oauth2::{CsrfToken, Scope, TwitchToken, UserTokenBuilder};
 use url::Url;
 
 pub async fn eventsub_callback(
     State(platform): State<Arc<super::Twitch>>,
-    Extension(message_tx): Extension<mpsc::Sender<IncomingMessage>>,
+    Extension(message_tx): Extension<mpsc::Sender<IncomingEvent>>,
     request: http::Request<axum::body::Body>,
 ) -> Result<String, (StatusCode, String)> {
     let (parts, body) = request.into_parts();
@@ -27,6 +32,20 @@ pub async fn eventsub_callback(
         .to_bytes();
     let request = http::Request::from_parts(parts, body);
 
+    let timestamp = request
+        .headers()
+        .get("Twitch-Eventsub-Message-Timestamp")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc3339(value).ok())
+        .map_or_else(Utc::now, |timestamp| timestamp.with_timezone(&Utc));
+
+    let event_id = request
+        .headers()
+        .get("Twitch-Eventsub-Message-Id")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+
     let valid =
         eventsub::Event::verify_payload(&request, platform.config.eventsub_secret.as_bytes());
     if !valid {
@@ -35,19 +54,87 @@ pub async fn eventsub_callback(
 
     match eventsub::Event::parse_http(&request) {
         Ok(event) => match event {
-            eventsub::Event::ChannelChatMessageV1(payload) => match payload.message {
-                eventsub::Message::Notification(notification) => {
-                    if let Err(err) = platform.handle_message(notification, message_tx).await {
-                        error!("Could not handle message: {err}");
-                    }
-                    Ok(String::new())
-                }
-                eventsub::Message::VerificationRequest(verification) => Ok(verification.challenge),
-                other => {
-                    warn!("Got unexpected message {other:?}, skipping",);
-                    Ok(String::new())
-                }
-            },
+            eventsub::Event::ChannelChatMessageV1(payload) => {
+                respond(payload.message, |notification| {
+                    platform.handle_message(notification, timestamp, message_tx)
+                })
+                .await
+            }
+            eventsub::Event::ChannelChatMessageDeleteV1(payload) => {
+                respond(payload.message, |notification| {
+                    platform.handle_message_delete(notification, message_tx)
+                })
+                .await
+            }
+            eventsub::Event::ChannelChatClearUserMessagesV1(payload) => {
+                respond(payload.message, |notification| {
+                    platform.handle_clear_user_messages(notification, message_tx)
+                })
+                .await
+            }
+            eventsub::Event::StreamOnlineV1(payload) => {
+                respond(payload.message, |notification| {
+                    let event = ChannelEvent::StreamOnline;
+                    platform.handle_channel_event(
+                        event,
+                        event_id,
+                        timestamp,
+                        notification.broadcaster_user_id.to_string(),
+                        notification.broadcaster_user_name.to_string(),
+                        message_tx,
+                    )
+                })
+                .await
+            }
+            eventsub::Event::StreamOfflineV1(payload) => {
+                respond(payload.message, |notification| {
+                    let event = ChannelEvent::StreamOffline;
+                    platform.handle_channel_event(
+                        event,
+                        event_id,
+                        timestamp,
+                        notification.broadcaster_user_id.to_string(),
+                        notification.broadcaster_user_name.to_string(),
+                        message_tx,
+                    )
+                })
+                .await
+            }
+            eventsub::Event::ChannelRaidV1(payload) => {
+                respond(payload.message, |notification| {
+                    let event = ChannelEvent::Raid {
+                        user_name: notification.from_broadcaster_user_name.to_string(),
+                        viewers: notification.viewers,
+                    };
+                    platform.handle_channel_event(
+                        event,
+                        event_id,
+                        timestamp,
+                        notification.to_broadcaster_user_id.to_string(),
+                        notification.to_broadcaster_user_name.to_string(),
+                        message_tx,
+                    )
+                })
+                .await
+            }
+            eventsub::Event::ChannelSubscribeV1(payload) => {
+                respond(payload.message, |notification| {
+                    let event = ChannelEvent::Subscription {
+                        user_name: notification.user_name.to_string(),
+                        tier: subscription_tier_name(&notification.tier),
+                        gifted: notification.is_gift,
+                    };
+                    platform.handle_channel_event(
+                        event,
+                        event_id,
+                        timestamp,
+                        notification.broadcaster_user_id.to_string(),
+                        notification.broadcaster_user_name.to_string(),
+                        message_tx,
+                    )
+                })
+                .await
+            }
             other => {
                 warn!(
                     "Got unexpected EventSub notification {:?}, skipping",
@@ -63,6 +150,40 @@ pub async fn eventsub_callback(
     }
 }
 
+fn subscription_tier_name(tier: &SubscriptionTier) -> String {
+    match tier {
+        SubscriptionTier::Tier1 => "Tier 1".to_owned(),
+        SubscriptionTier::Tier2 => "Tier 2".to_owned(),
+        SubscriptionTier::Tier3 => "Tier 3".to_owned(),
+        SubscriptionTier::Prime => "Prime".to_owned(),
+        SubscriptionTier::Other(other) => other.clone(),
+    }
+}
+
+/// Passes notifications to the handler and answers verification requests
+async fn respond<E, F>(
+    message: eventsub::Message<E>,
+    handler: impl FnOnce(E::Payload) -> F,
+) -> Result<String, (StatusCode, String)>
+where
+    E: EventSubscription + Clone + fmt::Debug,
+    F: Future<Output = anyhow::Result<()>>,
+{
+    match message {
+        eventsub::Message::Notification(notification) => {
+            if let Err(err) = handler(notification).await {
+                error!("Could not handle notification: {err:#}");
+            }
+            Ok(String::new())
+        }
+        eventsub::Message::VerificationRequest(verification) => Ok(verification.challenge),
+        other => {
+            warn!("Got unexpected message {other:?}, skipping",);
+            Ok(String::new())
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct AuthenticateParams {
     pub mode: AuthenticationMode,
@@ -73,6 +194,8 @@ pub struct AuthenticateParams {
 pub enum AuthenticationMode {
     Channel,
     User,
+    /// Lets the bridge send messages as a linked user
+    Sender,
 }
 
 impl fmt::Display for AuthenticationMode {
@@ -85,17 +208,32 @@ pub async fn auth(
     Query(params): Query<AuthenticateParams>,
     State(platform): State<Arc<super::Twitch>>,
 ) -> Redirect {
+    let scopes = match params.mode {
+        AuthenticationMode::Channel => vec![
+            Scope::ChannelBot,
+            // Needed for subscription events
+            Scope::ChannelReadSubscriptions,
+        ],
+        AuthenticationMode::User => vec![
+            Scope::UserBot,
+            Scope::UserReadChat,
+            Scope::UserWriteChat,
+            // Used by the bot to remove copies of deleted messages
+            Scope::ModeratorManageChatMessages,
+        ],
+        AuthenticationMode::Sender => vec![Scope::UserWriteChat],
+    };
+    authorize(&platform, scopes)
+}
+
+/// Sends the user to Twitch to log in with the given scopes
+fn authorize(platform: &super::Twitch, scopes: Vec<Scope>) -> Redirect {
     let redirect_url = Url::parse(&format!(
         "{}/platform/twitch/auth/redirect",
         platform.base_url
     ))
     .unwrap();
 
-    let scopes = match params.mode {
-        AuthenticationMode::Channel => vec![Scope::ChannelBot],
-        AuthenticationMode::User => vec![Scope::UserBot, Scope::UserReadChat, Scope::UserWriteChat],
-    };
-
     let mut builder = UserTokenBuilder::new(
         platform.config.client_id.clone(),
         platform.config.client_secret.clone(),
@@ -126,9 +264,9 @@ pub async fn auth_redirect(
     Query(params): Query<AuthRedirectParams>,
     Extension(db): Extension<DbPool>,
     State(platform): State<Arc<super::Twitch>>,
-) -> (StatusCode, String) {
+) -> Response {
     if let Some(err) = params.error_description {
-        return (StatusCode::UNPROCESSABLE_ENTITY, err);
+        return (StatusCode::UNPROCESSABLE_ENTITY, err).into_response();
     }
 
     let given_token = CsrfToken::new(params.state);
@@ -137,13 +275,15 @@ pub async fn auth_redirect(
         return (
             StatusCode::UNAUTHORIZED,
             "Invalid state provided".to_owned(),
-        );
+        )
+            .into_response();
     };
     let (Some(code), Some(scopes)) = (params.code, params.scope) else {
         return (
             StatusCode::BAD_REQUEST,
             "missing code or scopes param".to_owned(),
-        );
+        )
+            .into_response();
     };
 
     match builder
@@ -151,33 +291,66 @@ pub async fn auth_redirect(
         .await
     {
         Ok(user_token) => {
+            let expires_at = Utc::now().timestamp() + user_token.expires_in().as_secs() as i64;
             let refresh_token = user_token.refresh_token.expect("Missing refresh token");
             let refresh_token_str = refresh_token.as_str();
             let access_token = user_token.access_token.as_str();
             let user_id = user_token.user_id.as_str();
 
+            // A token only has the scopes it was requested with, so a login with another mode would
+            // drop the scopes of the user's existing login. The user logs in again with all of them instead.
+            let existing_scopes =
+                sqlx::query_scalar!("SELECT scopes FROM twitch_login WHERE user_id = ?", user_id)
+                    .fetch_optional(&db)
+                    .await
+                    .expect("DB error");
+            if let Some(existing_scopes) = existing_scopes {
+                let granted = scopes.split(' ').collect::<Vec<_>>();
+                let missing = existing_scopes
+                    .split(' ')
+                    .filter(|scope| !scope.is_empty() && !granted.contains(scope))
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    info!(
+                        "Login of user '{}' is missing the scopes {} of their previous login, asking for all of them",
+                        user_token.login,
+                        missing.join(" ")
+                    );
+                    let all_scopes = granted
+                        .into_iter()
+                        .chain(missing)
+                        .map(|scope| Scope::parse(scope.to_owned()))
+                        .collect();
+                    return authorize(&platform, all_scopes).into_response();
+                }
+            }
+
             sqlx::query!(
                 "
-                INSERT INTO twitch_login(user_id, access_token, refresh_token, scopes) 
-                VALUES (?1, ?2, ?3, ?4)
+                INSERT INTO twitch_login(user_id, access_token, refresh_token, scopes, expires_at) 
+                VALUES (?1, ?2, ?3, ?4, ?5)
                 ON CONFLICT(user_id) DO UPDATE
-                SET access_token = ?2, refresh_token = ?3, scopes = ?4",
+                SET access_token = ?2, refresh_token = ?3, scopes = ?4, expires_at = ?5,
+                    needs_reauth = FALSE",
                 user_id,
                 access_token,
                 refresh_token_str,
                 scopes,
+                expires_at,
             )
             .execute(&db)
             .await
             .expect("DB error");
             info!("Saved auth for user '{}'", user_token.login);
+            // A previous login of a linked user is replaced by the new one
+            platform.user_tokens.lock().await.remove(user_id);
 
             tokio::spawn(async move {
                 if let Err(err) = platform.setup_eventsub().await {
                     error!("Could not reconfigure EventSub: {err:#}");
                 }
             });
-            (StatusCode::OK, "Authentication succesful".to_owned())
+            (StatusCode::OK, "Authentication succesful".to_owned()).into_response()
         }
         Err(err) => {
             warn!("Could not trade token: {err}");
@@ -185,6 +358,7 @@ pub async fn auth_redirect(
                 StatusCode::UNPROCESSABLE_ENTITY,
                 "Could not trade token".to_owned(),
             )
+                .into_response()
         }
     }
 }
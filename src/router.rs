@@ -1,65 +1,1210 @@
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use chrono::{
+    format::{Item, StrftimeItems},
+    Utc,
+};
 use regex::Regex;
+use tracing::{debug, info, warn};
 
 use crate::{
+    commands::{
+        Command, CommandRegistry, CommandRequest, BRIDGE_COMMAND, HELP_COMMAND, LINK_COMMAND,
+        UNLINK_COMMAND,
+    },
     config::{self, FilterMode},
-    ChannelIdentifier,
+    events::{default_event_template, EventKind},
+    message_body::{BodyRenderer, MessageBody, Segment},
+    message_mapping::MessageCopy,
+    metrics,
+    template::{MessageTemplates, Template, TemplateValues},
+    user_filters::{UserFilterKind, UserFilters},
+    user_links::{LinkedUser, UserLinks},
+    ChannelIdentifier, IncomingMessage, MessageDeletion, OutgoingDeletion, OutgoingMessage,
+    Provenance, UserIdentifier,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
 };
-use std::{collections::HashMap, str::FromStr};
+
+/// The router is replaced when the config gets reloaded
+pub type SharedRouter = Arc<RwLock<MessageRouter>>;
 
 pub struct MessageRouter {
     pub channel_links: HashMap<ChannelIdentifier, Vec<MirroredChannel>>,
+    pub bridges: Vec<BridgeInfo>,
+    /// Command settings of every bridge the channel is part of
+    command_channels: HashMap<ChannelIdentifier, Vec<BridgeCommands>>,
+    message_config: config::Message,
+    pub state: RouterState,
+}
+
+#[derive(Debug, Clone)]
+pub struct BridgeInfo {
+    pub channels: Vec<ChannelIdentifier>,
+    pub bidirectional: bool,
+}
+
+/// Changes made through the bridge command, carried over when the config is reloaded
+#[derive(Default, Debug)]
+pub struct RouterState {
+    /// Nothing is mirrored from or to these channels
+    pub paused_channels: HashSet<ChannelIdentifier>,
+    /// (channel, user id) -> user name, messages of the user in that channel are not mirrored
+    pub muted_users: HashMap<(ChannelIdentifier, String), String>,
+}
+
+#[derive(Debug)]
+struct BridgeCommands {
+    /// All commands are enabled when not set
+    enabled: Option<Vec<String>>,
+    platforms: Vec<String>,
+}
+
+impl BridgeCommands {
+    fn is_enabled(&self, name: &str) -> bool {
+        self.enabled
+            .as_ref()
+            .is_none_or(|enabled| enabled.iter().any(|enabled| enabled == name))
+    }
+}
+
+/// Routing state that is not part of the config
+pub struct RouterContext {
+    pub zws_support: HashMap<&'static str, bool>,
+    pub deletion_support: HashMap<&'static str, bool>,
+    pub body_renderers: HashMap<&'static str, BodyRenderer>,
+    pub commands: CommandRegistry,
+    pub default_templates: HashMap<&'static str, MessageTemplates>,
+    pub user_links: UserLinks,
+    pub user_filters: UserFilters,
+    pub filter_hits: FilterHits,
+}
+
+/// Why a message was not mirrored to a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FilterReason {
+    BlockedUser,
+    MutedUser,
+    NotAllowedUser,
+    ExcludeFilter,
+    IncludeFilter,
+}
+
+impl FilterReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::BlockedUser => "blocked_user",
+            Self::MutedUser => "muted_user",
+            Self::NotAllowedUser => "not_allowed_user",
+            Self::ExcludeFilter => "exclude_filter",
+            Self::IncludeFilter => "include_filter",
+        }
+    }
+}
+
+/// Counts the messages dropped by filters since startup, by channel and reason
+#[derive(Clone, Default)]
+pub struct FilterHits(Arc<Mutex<HashMap<(ChannelIdentifier, FilterReason), u64>>>);
+
+impl FilterHits {
+    /// `filter` is the pattern of the matching exclude filter, it is only used for metrics
+    fn record(&self, channel: &ChannelIdentifier, reason: FilterReason, filter: Option<&str>) {
+        metrics::MESSAGES_FILTERED.inc(&[
+            &channel.to_string(),
+            reason.as_str(),
+            filter.unwrap_or_default(),
+        ]);
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry((channel.clone(), reason))
+            .or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> Vec<(ChannelIdentifier, FilterReason, u64)> {
+        let mut hits: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((channel, reason), count)| (channel.clone(), *reason, *count))
+            .collect();
+        hits.sort_by_key(|(_, _, count)| std::cmp::Reverse(*count));
+        hits
+    }
 }
 
 impl MessageRouter {
-    pub fn new(config: &[config::Bridge]) -> anyhow::Result<Self> {
+    pub fn new(config: &config::Config) -> anyhow::Result<Self> {
         let mut channel_links: HashMap<ChannelIdentifier, Vec<MirroredChannel>> = HashMap::new();
+        let mut command_channels: HashMap<ChannelIdentifier, Vec<BridgeCommands>> = HashMap::new();
+        let mut bridges = Vec::with_capacity(config.bridge.len());
+
+        if StrftimeItems::new(&config.message.timestamp_format).any(|item| item == Item::Error) {
+            return Err(anyhow!(
+                "Invalid timestamp format '{}'",
+                config.message.timestamp_format
+            ));
+        }
+
+        for bridge_config in &config.bridge {
+            if bridge_config.channels.len() < 2 {
+                return Err(anyhow!("A bridge needs at least 2 channels"));
+            }
 
-        for bridge_config in config {
-            let [source, target] = &bridge_config.channels;
             let bidirectional = bridge_config.bidirectional.unwrap_or(true);
-            let insert_zws = bridge_config.insert_zws_into_names.unwrap_or(false);
 
-            let exclude_filters: Vec<Regex> = bridge_config
-                .exclude_filters
+            let members = bridge_config
+                .channels
                 .iter()
-                .map(|filter| Regex::new(filter).context("Invalid regex"))
-                .collect::<anyhow::Result<_>>()?;
-
-            let source_channel = ChannelIdentifier::from_str(source).unwrap();
-            let target_channel = ChannelIdentifier::from_str(target).unwrap();
-
-            channel_links
-                .entry(source_channel.clone())
-                .or_default()
-                .push(MirroredChannel {
-                    channel: target_channel.clone(),
-                    insert_zws,
-                    exclude_filters: exclude_filters.clone(),
-                    filter_mode: bridge_config.filter_mode,
-                });
+                .map(|channel| MirroredChannel::new(&config.message, bridge_config, channel))
+                .collect::<anyhow::Result<Vec<_>>>()?;
 
-            if bidirectional {
-                channel_links
-                    .entry(target_channel)
+            for (i, member) in members.iter().enumerate() {
+                if !config.platforms.contains_key(&member.channel.platform) {
+                    return Err(anyhow!(
+                        "Platform {} of channel {} is not configured",
+                        member.channel.platform,
+                        member.channel
+                    ));
+                }
+                if members[..i]
+                    .iter()
+                    .any(|other| other.channel == member.channel)
+                {
+                    return Err(anyhow!(
+                        "Channel {} is listed multiple times in the same bridge",
+                        member.channel
+                    ));
+                }
+            }
+
+            bridges.push(BridgeInfo {
+                channels: members
+                    .iter()
+                    .map(|member| member.channel.clone())
+                    .collect(),
+                bidirectional,
+            });
+
+            // Commands are answered in the requesting channel only, so they work in every member
+            for member in &members {
+                command_channels
+                    .entry(member.channel.clone())
                     .or_default()
-                    .push(MirroredChannel {
-                        channel: source_channel,
-                        insert_zws,
-                        exclude_filters,
-                        filter_mode: bridge_config.filter_mode,
+                    .push(BridgeCommands {
+                        enabled: bridge_config.commands.clone(),
+                        platforms: members
+                            .iter()
+                            .map(|member| member.channel.platform.clone())
+                            .collect(),
                     });
             }
+
+            let sources = if bidirectional {
+                &members[..]
+            } else {
+                &members[..1]
+            };
+
+            for source in sources {
+                let targets = members
+                    .iter()
+                    .filter(|target| target.channel != source.channel)
+                    .cloned();
+
+                channel_links
+                    .entry(source.channel.clone())
+                    .or_default()
+                    .extend(targets);
+            }
         }
 
-        Ok(Self { channel_links })
+        Ok(Self {
+            channel_links,
+            bridges,
+            command_channels,
+            message_config: config.message.clone(),
+            state: RouterState::default(),
+        })
+    }
+
+    /// Channel ids of the given platform that messages are mirrored from
+    pub fn platform_channels(&self, platform: &str) -> Vec<String> {
+        let mut channels = self
+            .channel_links
+            .keys()
+            .filter(|channel| channel.platform == platform)
+            .filter_map(|channel| channel.value.clone())
+            .collect::<Vec<String>>();
+        channels.sort();
+        channels
+    }
+
+    /// Channel ids of the given platform that are part of a bridge, including channels that are only mirrored to
+    pub fn bridged_channels(&self, platform: &str) -> Vec<String> {
+        let mut channels = self
+            .bridges
+            .iter()
+            .flat_map(|bridge| &bridge.channels)
+            .filter(|channel| channel.platform == platform)
+            .filter_map(|channel| channel.value.clone())
+            .collect::<Vec<String>>();
+        channels.sort();
+        channels.dedup();
+        channels
+    }
+
+    /// Returns the command the message invokes, if it is available in the channel it was sent in
+    pub fn find_command(
+        &self,
+        ctx: &RouterContext,
+        source_platform: &'static str,
+        incoming_msg: &IncomingMessage,
+    ) -> Option<Command> {
+        if incoming_msg.provenance.is_some() || incoming_msg.event.is_some() {
+            return None;
+        }
+
+        let prefix = &self.message_config.command_prefix;
+        let text = incoming_msg.body.plain_text();
+        let invocation = text.strip_prefix(prefix.as_str())?;
+        let (name, args) = invocation.split_once(' ').unwrap_or((invocation, ""));
+
+        let channel = ChannelIdentifier {
+            platform: source_platform.to_owned(),
+            value: incoming_msg.channel_id.clone(),
+        };
+        let bridges = self.command_channels.get(&channel)?;
+
+        let is_available = |name: &str, platform: &str| {
+            bridges.iter().any(|bridge| {
+                bridge.is_enabled(name) && bridge.platforms.iter().any(|member| member == platform)
+            })
+        };
+
+        let is_builtin_enabled = |name: &str| bridges.iter().any(|bridge| bridge.is_enabled(name));
+
+        let request = CommandRequest {
+            name: name.to_owned(),
+            args: args.trim().to_owned(),
+            channel,
+            user_id: incoming_msg.user_id.clone(),
+            user_name: incoming_msg.user_name.clone(),
+            user_is_admin: incoming_msg.user_is_admin,
+            mentioned_users: incoming_msg.body.mentioned_users(),
+        };
+
+        match name {
+            HELP_COMMAND => {
+                if !is_builtin_enabled(HELP_COMMAND) {
+                    return None;
+                }
+                let mut available: Vec<_> = ctx
+                    .commands
+                    .iter()
+                    .filter(|(name, command)| is_available(name, command.platform))
+                    .map(|(name, command)| (name, command.description))
+                    .collect();
+                if is_builtin_enabled(BRIDGE_COMMAND) {
+                    available.push((BRIDGE_COMMAND, "manages the bridge, for moderators"));
+                }
+                if is_builtin_enabled(LINK_COMMAND) {
+                    available.push((LINK_COMMAND, "links your account on another platform"));
+                }
+                if is_builtin_enabled(UNLINK_COMMAND) {
+                    available.push((UNLINK_COMMAND, "removes your account links"));
+                }
+                Some(Command::Help {
+                    prefix: prefix.clone(),
+                    available,
+                })
+            }
+            BRIDGE_COMMAND => {
+                is_builtin_enabled(BRIDGE_COMMAND).then_some(Command::Bridge { request })
+            }
+            LINK_COMMAND => is_builtin_enabled(LINK_COMMAND).then(|| Command::Link {
+                prefix: prefix.clone(),
+                request,
+                user_links: ctx.user_links.clone(),
+            }),
+            UNLINK_COMMAND => is_builtin_enabled(UNLINK_COMMAND).then(|| Command::Unlink {
+                request,
+                user_links: ctx.user_links.clone(),
+            }),
+            _ => {
+                let command = ctx.commands.get(name)?;
+                if !is_available(name, command.platform) {
+                    debug!("Command {name} is not available in {}", request.channel);
+                    return None;
+                }
+                Some(Command::Platform {
+                    handler: command.handler.clone(),
+                    request,
+                })
+            }
+        }
+    }
+
+    /// Handles `bridge status`, `pause`, `resume`, `mute @user` and `unmute @user` in the requesting channel.
+    /// Users are muted by id and only in the requesting channel, the one the moderator is allowed to manage.
+    pub fn run_bridge_command(&mut self, request: &CommandRequest) -> String {
+        let (action, user) = request.args.split_once(' ').unwrap_or((&request.args, ""));
+        let user = user.trim();
+        let channel = &request.channel;
+        let mentioned_user = request.mentioned_users.first().cloned();
+
+        match (action, user.is_empty()) {
+            ("status", _) => {
+                let mirroring = if self.state.paused_channels.contains(channel) {
+                    "paused"
+                } else {
+                    "active"
+                };
+                let targets = self
+                    .channel_links
+                    .get(channel)
+                    .map(|targets| {
+                        targets
+                            .iter()
+                            .map(|target| target.channel.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                    .unwrap_or_default();
+                let muted_users = self
+                    .state
+                    .muted_users
+                    .keys()
+                    .filter(|(muted_channel, _)| muted_channel == channel)
+                    .count();
+                format!(
+                    "Mirroring is {mirroring}, linked to: {targets}. Muted users: {muted_users}"
+                )
+            }
+            ("pause", _) => {
+                info!("Mirroring in {channel} paused by {:?}", request.user_name);
+                self.state.paused_channels.insert(channel.clone());
+                "Mirroring paused for this channel".to_owned()
+            }
+            ("resume", _) => {
+                info!("Mirroring in {channel} resumed by {:?}", request.user_name);
+                self.state.paused_channels.remove(channel);
+                "Mirroring resumed for this channel".to_owned()
+            }
+            ("mute", false) => {
+                let Some((user_id, user_name)) = mentioned_user else {
+                    return self.mention_usage("mute");
+                };
+                info!(
+                    "User {user_id} ({user_name}) muted in {channel} by {:?}",
+                    request.user_name
+                );
+                let reply = format!("Messages from {user_name} are no longer mirrored");
+                self.state
+                    .muted_users
+                    .insert((channel.clone(), user_id), user_name);
+                reply
+            }
+            ("unmute", false) => {
+                // Muted users can also be named, in case they left or changed their name since
+                let muted = mentioned_user.or_else(|| {
+                    let name = user.trim_start_matches('@');
+                    self.state
+                        .muted_users
+                        .iter()
+                        .find(|((muted_channel, _), muted_name)| {
+                            muted_channel == channel && muted_name.eq_ignore_ascii_case(name)
+                        })
+                        .map(|((_, user_id), muted_name)| (user_id.clone(), muted_name.clone()))
+                });
+                let Some((user_id, user_name)) = muted else {
+                    return self.mention_usage("unmute");
+                };
+                info!(
+                    "User {user_id} ({user_name}) unmuted in {channel} by {:?}",
+                    request.user_name
+                );
+                self.state.muted_users.remove(&(channel.clone(), user_id));
+                format!("Messages from {user_name} are mirrored again")
+            }
+            _ => format!(
+                "Usage: {}{BRIDGE_COMMAND} status|pause|resume|mute @user|unmute @user",
+                self.message_config.command_prefix
+            ),
+        }
+    }
+
+    fn mention_usage(&self, action: &str) -> String {
+        format!(
+            "Mention the user, e.g. {}{BRIDGE_COMMAND} {action} @name",
+            self.message_config.command_prefix
+        )
+    }
+
+    /// Builds a message that is only sent to the channel of the incoming message, e.g. the reply to a command
+    pub fn direct_message(
+        &self,
+        source_platform: &str,
+        incoming_msg: &IncomingMessage,
+        contents: String,
+    ) -> OutgoingMessage {
+        let channel = ChannelIdentifier {
+            platform: source_platform.to_owned(),
+            value: incoming_msg.channel_id.clone(),
+        };
+        // Reaching the hop limit right away keeps the message from being mirrored when the platform echoes it back
+        let provenance = Provenance {
+            origin_platform: source_platform.to_owned(),
+            origin_message_id: Some(incoming_msg.id.clone()),
+            hop_count: self.message_config.max_hops,
+            visited_channels: vec![channel],
+        };
+
+        OutgoingMessage {
+            source_platform: source_platform.to_owned(),
+            target_channel_id: incoming_msg.channel_id.clone(),
+            sender_user_id: None,
+            content: contents.clone(),
+            unformatted_content: contents,
+            source_msg: incoming_msg.clone(),
+            provenance,
+            history_id: None,
+            expires_at: None,
+        }
+    }
+
+    /// Builds the messages that should be sent for an incoming message, along with the platform to send each one to
+    pub fn route(
+        &self,
+        ctx: &RouterContext,
+        source_platform: &'static str,
+        incoming_msg: &IncomingMessage,
+    ) -> Vec<(String, OutgoingMessage)> {
+        let mut outgoing_messages = Vec::new();
+
+        let identifier = ChannelIdentifier {
+            platform: source_platform.to_owned(),
+            value: incoming_msg.channel_id.clone(),
+        };
+
+        let user_filter = incoming_msg.user_id.as_ref().and_then(|user_id| {
+            ctx.user_filters.get(&UserIdentifier {
+                platform: source_platform.to_owned(),
+                user_id: user_id.clone(),
+            })
+        });
+        if user_filter == Some(UserFilterKind::Block) {
+            debug!("User of message {incoming_msg:?} is blocked, not mirroring it");
+            ctx.filter_hits
+                .record(&identifier, FilterReason::BlockedUser, None);
+            return outgoing_messages;
+        }
+
+        let Some(target_channels) = self.channel_links.get(&identifier) else {
+            return outgoing_messages;
+        };
+
+        if self.state.paused_channels.contains(&identifier) {
+            debug!("Mirroring from {identifier} is paused");
+            return outgoing_messages;
+        }
+        if let Some(user_id) = &incoming_msg.user_id {
+            if self
+                .state
+                .muted_users
+                .contains_key(&(identifier.clone(), user_id.clone()))
+            {
+                debug!("User {user_id} is muted in {identifier}, not mirroring the message");
+                ctx.filter_hits
+                    .record(&identifier, FilterReason::MutedUser, None);
+                return outgoing_messages;
+            }
+        }
+
+        let provenance = incoming_msg
+            .provenance
+            .clone()
+            .unwrap_or_else(|| Provenance {
+                origin_platform: source_platform.to_owned(),
+                origin_message_id: Some(incoming_msg.id.clone()),
+                hop_count: 0,
+                visited_channels: vec![identifier.clone()],
+            });
+
+        if provenance.hop_count >= self.message_config.max_hops {
+            debug!(
+                "Message {:?} originally from {} reached the hop limit, not mirroring it",
+                provenance.origin_message_id, provenance.origin_platform
+            );
+            return outgoing_messages;
+        }
+
+        let user_name = incoming_msg
+            .user_id
+            .as_ref()
+            .and_then(|user_id| {
+                ctx.user_links.display_name(&UserIdentifier {
+                    platform: source_platform.to_owned(),
+                    user_id: user_id.to_owned(),
+                })
+            })
+            .or_else(|| incoming_msg.user_name.clone());
+
+        // All targets are marked as visited up front, so a copy echoed back by one target is not sent to the others again
+        let mut outgoing_provenance = provenance.clone();
+        outgoing_provenance.hop_count += 1;
+        for target_channel in target_channels {
+            if !outgoing_provenance
+                .visited_channels
+                .contains(&target_channel.channel)
+            {
+                outgoing_provenance
+                    .visited_channels
+                    .push(target_channel.channel.clone());
+            }
+        }
+
+        let timestamp = incoming_msg
+            .timestamp
+            .with_timezone(&chrono::Local)
+            .format(&self.message_config.timestamp_format)
+            .to_string();
+        let message_age = Utc::now() - incoming_msg.timestamp;
+
+        let platform = self
+            .message_config
+            .platform_aliases
+            .get(source_platform)
+            .map(|s| s.as_str())
+            .unwrap_or(source_platform);
+
+        debug!("Mirroring message {incoming_msg:?} to channels {target_channels:?}");
+        'target_channels: for target_channel in target_channels {
+            if self.state.paused_channels.contains(&target_channel.channel) {
+                debug!("Mirroring to {} is paused", target_channel.channel);
+                continue;
+            }
+
+            if provenance
+                .visited_channels
+                .contains(&target_channel.channel)
+            {
+                debug!(
+                    "Message already passed through {}, not mirroring it again",
+                    target_channel.channel
+                );
+                continue;
+            }
+
+            if target_channel
+                .max_message_age
+                .is_some_and(|max_message_age| message_age > max_message_age)
+            {
+                debug!(
+                    "Message {incoming_msg:?} is too old to be mirrored to {}",
+                    target_channel.channel
+                );
+                continue;
+            }
+
+            if target_channel.allowed_users_only
+                && incoming_msg.user_id.is_some()
+                && user_filter != Some(UserFilterKind::Allow)
+            {
+                debug!(
+                    "User of message {incoming_msg:?} is not allowed in {}",
+                    target_channel.channel
+                );
+                ctx.filter_hits
+                    .record(&target_channel.channel, FilterReason::NotAllowedUser, None);
+                continue;
+            }
+
+            let target_platform = target_channel.channel.platform.as_str();
+            let (Some(platform_templates), Some(render_body), Some(&platform_supports_zws)) = (
+                ctx.default_templates.get(target_platform),
+                ctx.body_renderers.get(target_platform),
+                ctx.zws_support.get(target_platform),
+            ) else {
+                warn!(
+                    "Platform {target_platform} is not running, not mirroring to {}",
+                    target_channel.channel
+                );
+                continue;
+            };
+
+            let source_body = match &incoming_msg.event {
+                Some(event) => {
+                    let kind = event.kind();
+                    if !target_channel.events.contains(&kind) {
+                        debug!(
+                            "{kind:?} events are not mirrored to {}",
+                            target_channel.channel
+                        );
+                        continue;
+                    }
+
+                    let details = event.details().unwrap_or_default();
+                    let event_values = TemplateValues {
+                        platform,
+                        name: event.user_name(),
+                        color: None,
+                        channel: incoming_msg
+                            .channel_name
+                            .as_deref()
+                            .or(incoming_msg.channel_id.as_deref()),
+                        timestamp: &timestamp,
+                        message_id: &incoming_msg.id,
+                        contents: &details,
+                    };
+                    let description = target_channel
+                        .event_templates
+                        .get(&kind)
+                        .unwrap_or_else(|| default_event_template(kind))
+                        .render(&event_values);
+                    Cow::Owned(MessageBody::from_text(&description))
+                }
+                None => Cow::Borrowed(&incoming_msg.body),
+            };
+            let source_body = target_channel.rewrite_body(&source_body);
+
+            let body = translate_mentions(
+                ctx,
+                &source_body,
+                source_platform,
+                &target_channel.channel.platform,
+            );
+            let contents = render_body(&body);
+            let source_text = body.plain_text();
+
+            let mut values = TemplateValues {
+                platform,
+                name: None,
+                color: incoming_msg.user_color.as_deref(),
+                channel: incoming_msg
+                    .channel_name
+                    .as_deref()
+                    .or(incoming_msg.channel_id.as_deref()),
+                timestamp: &timestamp,
+                message_id: &incoming_msg.id,
+                contents: &contents,
+            };
+
+            let content = match user_name.clone() {
+                Some(mut name) => {
+                    if target_channel.insert_zws && name.len() > 1 && platform_supports_zws {
+                        let magic_char = char::from_u32(0x000E0000).unwrap();
+                        name.insert(1, magic_char);
+                    }
+
+                    values.name = Some(&name);
+                    target_channel
+                        .template
+                        .as_ref()
+                        .unwrap_or(&platform_templates.user)
+                        .render(&values)
+                }
+                None => target_channel
+                    .system_template
+                    .as_ref()
+                    .unwrap_or(&platform_templates.system)
+                    .render(&values),
+            };
+
+            let content = target_channel
+                .rewrite(&content, FilterMode::FinalMessage)
+                .into_owned();
+
+            let filter_haystack = match target_channel.filter_mode {
+                FilterMode::FinalMessage => &content,
+                FilterMode::SourceMessage => &source_text,
+            };
+            for exclude_filter in &target_channel.exclude_filters {
+                if exclude_filter.is_match(filter_haystack) {
+                    debug!(
+                        "Message '{content}' to {} filtered out by {exclude_filter}",
+                        target_channel.channel
+                    );
+                    ctx.filter_hits.record(
+                        &target_channel.channel,
+                        FilterReason::ExcludeFilter,
+                        Some(exclude_filter.as_str()),
+                    );
+                    continue 'target_channels;
+                }
+            }
+            if !target_channel.include_filters.is_empty()
+                && !target_channel
+                    .include_filters
+                    .iter()
+                    .any(|include_filter| include_filter.is_match(filter_haystack))
+            {
+                debug!(
+                    "Message '{content}' to {} does not match any include filter",
+                    target_channel.channel
+                );
+                ctx.filter_hits
+                    .record(&target_channel.channel, FilterReason::IncludeFilter, None);
+                continue;
+            }
+
+            let sender_user_id = incoming_msg
+                .user_id
+                .as_ref()
+                .and_then(|source_user_id| {
+                    ctx.user_links.get(
+                        &UserIdentifier {
+                            platform: source_platform.to_owned(),
+                            user_id: source_user_id.to_owned(),
+                        },
+                        &target_channel.channel.platform,
+                    )
+                })
+                .map(|linked_user| linked_user.user_id);
+
+            let outgoing_message = OutgoingMessage {
+                source_platform: source_platform.to_owned(),
+                content,
+                unformatted_content: contents,
+                target_channel_id: target_channel.channel.value.clone(),
+                sender_user_id,
+                source_msg: incoming_msg.clone(),
+                provenance: outgoing_provenance.clone(),
+                history_id: None,
+                expires_at: target_channel
+                    .max_message_age
+                    .map(|max_message_age| incoming_msg.timestamp + max_message_age),
+            };
+            outgoing_messages.push((target_channel.channel.platform.clone(), outgoing_message));
+        }
+
+        outgoing_messages
+    }
+
+    /// Picks the copies of deleted messages that should be removed as well, along with the platform of each one
+    pub fn route_deletion(
+        &self,
+        ctx: &RouterContext,
+        source_platform: &'static str,
+        deletion: &MessageDeletion,
+        copies: Vec<MessageCopy>,
+    ) -> Vec<(String, OutgoingDeletion)> {
+        let identifier = ChannelIdentifier {
+            platform: source_platform.to_owned(),
+            value: deletion.channel_id.clone(),
+        };
+        let Some(target_channels) = self.channel_links.get(&identifier) else {
+            return Vec::new();
+        };
+
+        copies
+            .into_iter()
+            .filter(|copy| {
+                let platform_supports_deletion = ctx
+                    .deletion_support
+                    .get(copy.target_platform.as_str())
+                    .copied()
+                    .unwrap_or(false);
+
+                platform_supports_deletion
+                    && target_channels.iter().any(|target_channel| {
+                        target_channel.propagate_deletions
+                            && target_channel.channel.platform == copy.target_platform
+                            && target_channel.channel.value == copy.target_channel_id
+                    })
+            })
+            .map(|copy| {
+                let outgoing_deletion = OutgoingDeletion {
+                    target_channel_id: copy.target_channel_id,
+                    message_id: copy.target_message_id,
+                };
+                (copy.target_platform, outgoing_deletion)
+            })
+            .collect()
     }
 }
 
+/// Points mentions of linked users to their account on the target platform.
+/// Other mentions are turned into plain names, so they don't notify an unrelated user with the same name.
+fn translate_mentions(
+    ctx: &RouterContext,
+    body: &MessageBody,
+    source_platform: &str,
+    target_platform: &str,
+) -> MessageBody {
+    if source_platform == target_platform {
+        return body.clone();
+    }
+
+    body.map_mentions(&|user_id, name| {
+        let linked_user = user_id.and_then(|user_id| {
+            let user = UserIdentifier {
+                platform: source_platform.to_owned(),
+                user_id: user_id.to_owned(),
+            };
+            ctx.user_links.get(&user, target_platform)
+        });
+
+        match linked_user {
+            Some(LinkedUser {
+                user_id,
+                user_name: Some(user_name),
+            }) => Segment::Mention {
+                user_id: Some(user_id),
+                name: user_name,
+            },
+            _ => Segment::Text(name.to_owned()),
+        }
+    })
+}
+
 #[derive(Clone, Debug)]
 pub struct MirroredChannel {
     pub channel: ChannelIdentifier,
     pub insert_zws: bool,
     pub exclude_filters: Vec<Regex>,
+    pub include_filters: Vec<Regex>,
+    pub allowed_users_only: bool,
     pub filter_mode: FilterMode,
+    pub rewrite_rules: Vec<RewriteRule>,
+    /// Platform defaults are used when not set
+    pub template: Option<Template>,
+    pub system_template: Option<Template>,
+    pub max_message_age: Option<chrono::Duration>,
+    pub propagate_deletions: bool,
+    pub events: Vec<EventKind>,
+    /// Default event templates are used for kinds that are not set
+    pub event_templates: HashMap<EventKind, Template>,
+}
+
+impl MirroredChannel {
+    fn new(
+        message_config: &config::Message,
+        bridge_config: &config::Bridge,
+        channel: &config::BridgeChannel,
+    ) -> anyhow::Result<Self> {
+        let overrides = channel.overrides();
+
+        let insert_zws = overrides
+            .and_then(|overrides| overrides.insert_zws_into_names)
+            .or(bridge_config.insert_zws_into_names)
+            .unwrap_or(false);
+
+        let filter_mode = overrides
+            .and_then(|overrides| overrides.filter_mode)
+            .unwrap_or(bridge_config.filter_mode);
+
+        let exclude_filters: Vec<Regex> = overrides
+            .and_then(|overrides| overrides.exclude_filters.as_ref())
+            .unwrap_or(&bridge_config.exclude_filters)
+            .iter()
+            .map(|filter| Regex::new(filter).context("Invalid regex"))
+            .collect::<anyhow::Result<_>>()?;
+
+        let include_filters: Vec<Regex> = overrides
+            .and_then(|overrides| overrides.include_filters.as_ref())
+            .unwrap_or(&bridge_config.include_filters)
+            .iter()
+            .map(|filter| Regex::new(filter).context("Invalid regex"))
+            .collect::<anyhow::Result<_>>()?;
+
+        let allowed_users_only = overrides
+            .and_then(|overrides| overrides.allowed_users_only)
+            .or(bridge_config.allowed_users_only)
+            .unwrap_or(false);
+
+        let rewrite_rules = overrides
+            .and_then(|overrides| overrides.rewrite_rules.as_ref())
+            .unwrap_or(&bridge_config.rewrite_rules)
+            .iter()
+            .map(|rule| {
+                Ok(RewriteRule {
+                    regex: Regex::new(&rule.pattern).context("Invalid rewrite rule regex")?,
+                    replacement: rule.replacement.clone(),
+                    mode: rule.mode.unwrap_or(filter_mode),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let template = overrides
+            .and_then(|overrides| overrides.template.as_deref())
+            .or(bridge_config.template.as_deref())
+            .or(message_config.template.as_deref())
+            .map(Template::from_str)
+            .transpose()
+            .context("Invalid template")?;
+
+        let system_template = overrides
+            .and_then(|overrides| overrides.system_template.as_deref())
+            .or(bridge_config.system_template.as_deref())
+            .or(message_config.system_template.as_deref())
+            .map(Template::parse_system)
+            .transpose()
+            .context("Invalid system template")?;
+
+        let max_message_age = overrides
+            .and_then(|overrides| overrides.max_message_age)
+            .or(bridge_config.max_message_age)
+            .map(|seconds| chrono::Duration::seconds(seconds as i64));
+
+        let propagate_deletions = overrides
+            .and_then(|overrides| overrides.propagate_deletions)
+            .or(bridge_config.propagate_deletions)
+            .unwrap_or(true);
+
+        let events = overrides
+            .and_then(|overrides| overrides.events.clone())
+            .or(bridge_config.events.clone())
+            .unwrap_or_else(|| EventKind::ALL.to_vec());
+
+        let event_templates = overrides
+            .and_then(|overrides| overrides.event_templates.as_ref())
+            .unwrap_or(&bridge_config.event_templates)
+            .iter()
+            .map(|(kind, template)| {
+                let template = Template::parse_event(template)
+                    .with_context(|| format!("Invalid template for {kind:?} events"))?;
+                Ok((*kind, template))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            channel: ChannelIdentifier::from_str(channel.channel()).unwrap(),
+            insert_zws,
+            exclude_filters,
+            include_filters,
+            allowed_users_only,
+            filter_mode,
+            rewrite_rules,
+            template,
+            system_template,
+            max_message_age,
+            propagate_deletions,
+            events,
+            event_templates,
+        })
+    }
+
+    /// Applies the source message rules to the text segments of the body, so mentions, emotes, links
+    /// and formatting are mirrored as they are
+    pub fn rewrite_body<'a>(&self, body: &'a MessageBody) -> Cow<'a, MessageBody> {
+        if !self
+            .rewrite_rules
+            .iter()
+            .any(|rule| rule.mode == FilterMode::SourceMessage)
+        {
+            return Cow::Borrowed(body);
+        }
+        let rewritten = body.map_text(&|text| self.rewrite(text, FilterMode::SourceMessage));
+        if rewritten == *body {
+            Cow::Borrowed(body)
+        } else {
+            Cow::Owned(rewritten)
+        }
+    }
+
+    /// Applies the rewrite rules for the given part of the message
+    pub fn rewrite<'a>(&self, text: &'a str, mode: FilterMode) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for rule in self.rewrite_rules.iter().filter(|rule| rule.mode == mode) {
+            if let Cow::Owned(rewritten) = rule.regex.replace_all(&text, rule.replacement.as_str())
+            {
+                text = Cow::Owned(rewritten);
+            }
+        }
+        text
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RewriteRule {
+    pub regex: Regex,
+    pub replacement: String,
+    pub mode: FilterMode,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_body::Style,
+        test_util::{account, mention, test_db, text},
+        DbPool,
+    };
+
+    async fn router_context(db: &DbPool) -> RouterContext {
+        RouterContext {
+            zws_support: HashMap::new(),
+            deletion_support: HashMap::new(),
+            body_renderers: HashMap::new(),
+            commands: CommandRegistry::default(),
+            default_templates: HashMap::new(),
+            user_links: UserLinks::load(db).await.unwrap(),
+            user_filters: UserFilters::load(db).await.unwrap(),
+            filter_hits: FilterHits::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn mentions_are_translated_to_linked_accounts() {
+        let ctx = router_context(&test_db().await).await;
+        ctx.user_links
+            .link(
+                &account("twitch", "1", Some("alice_tv")),
+                &account("factorio", "alice", Some("alice")),
+            )
+            .await
+            .unwrap();
+        // Without a name on the target platform the mention can't be translated
+        ctx.user_links
+            .link(
+                &account("twitch", "2", Some("bob_tv")),
+                &account("factorio", "bob", None),
+            )
+            .await
+            .unwrap();
+
+        let body = MessageBody {
+            segments: vec![
+                mention(Some("1"), "alice_tv"),
+                text(" "),
+                mention(Some("2"), "bob_tv"),
+                text(" "),
+                mention(None, "carol"),
+            ],
+        };
+        assert_eq!(
+            translate_mentions(&ctx, &body, "twitch", "factorio").segments,
+            [
+                mention(Some("alice"), "alice"),
+                text(" "),
+                text("bob_tv"),
+                text(" "),
+                text("carol"),
+            ]
+        );
+        assert_eq!(translate_mentions(&ctx, &body, "twitch", "twitch"), body);
+
+        let reply = MessageBody {
+            segments: vec![mention(Some("alice"), "alice")],
+        };
+        assert_eq!(
+            translate_mentions(&ctx, &reply, "factorio", "twitch").segments,
+            [mention(Some("1"), "alice_tv")]
+        );
+    }
+
+    fn mirrored_channel(bridge_config: &str) -> MirroredChannel {
+        let bridge_config: config::Bridge = toml::from_str(bridge_config).unwrap();
+        MirroredChannel::new(
+            &config::Message::default(),
+            &bridge_config,
+            &bridge_config.channels[0],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn source_rewrites_only_change_text() {
+        let channel = mirrored_channel(
+            r#"
+            channels = ["twitch:1", "factorio"]
+            [[rewrite_rules]]
+            pattern = '^!say '
+            replacement = ""
+            mode = "SourceMessage"
+            [[rewrite_rules]]
+            pattern = 'example'
+            replacement = "sample"
+            mode = "SourceMessage"
+            "#,
+        );
+        let body = MessageBody {
+            segments: vec![
+                text("!say hi "),
+                mention(Some("2"), "bob_tv"),
+                text(", see "),
+                Segment::Formatted {
+                    style: Style::Bold,
+                    segments: vec![text("example")],
+                },
+                text(" "),
+                Segment::Link {
+                    url: "https://example.com".to_owned(),
+                },
+            ],
+        };
+
+        assert_eq!(
+            channel.rewrite_body(&body).segments,
+            [
+                text("hi "),
+                mention(Some("2"), "bob_tv"),
+                text(", see "),
+                Segment::Formatted {
+                    style: Style::Bold,
+                    segments: vec![text("sample")],
+                },
+                text(" "),
+                Segment::Link {
+                    url: "https://example.com".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rewritten_messages_keep_mentions() {
+        let ctx = router_context(&test_db().await).await;
+        ctx.user_links
+            .link(
+                &account("twitch", "1", Some("alice_tv")),
+                &account("factorio", "alice", Some("alice")),
+            )
+            .await
+            .unwrap();
+        let channel = mirrored_channel(
+            r#"
+            channels = ["twitch:1", "factorio"]
+            [[rewrite_rules]]
+            pattern = '^!say '
+            replacement = ""
+            mode = "SourceMessage"
+            "#,
+        );
+        let body = MessageBody {
+            segments: vec![text("!say hi "), mention(Some("1"), "alice_tv")],
+        };
+
+        let rewritten = channel.rewrite_body(&body);
+        assert_eq!(
+            translate_mentions(&ctx, &rewritten, "twitch", "factorio").segments,
+            [text("hi "), mention(Some("alice"), "alice")]
+        );
+    }
+
+    #[test]
+    fn unmatched_source_rewrites_keep_the_body() {
+        let channel = mirrored_channel(
+            r#"
+            channels = ["twitch:1", "factorio"]
+            [[rewrite_rules]]
+            pattern = 'nothing'
+            replacement = ""
+            mode = "SourceMessage"
+            "#,
+        );
+        let body = MessageBody {
+            segments: vec![Segment::Emote {
+                name: "Kappa".to_owned(),
+            }],
+        };
+
+        assert!(matches!(channel.rewrite_body(&body), Cow::Borrowed(_)));
+    }
 }
@@ -0,0 +1,265 @@
+use anyhow::{anyhow, bail};
+use std::str::FromStr;
+
+/// A message format with `{placeholder}` substitutions.
+///
+/// A placeholder can have a fallback that is used when the value is not available, e.g. `{color|ffffff}`.
+/// Literal braces are written as `{{` and `}}`.
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Placeholder {
+        placeholder: Placeholder,
+        fallback: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placeholder {
+    Platform,
+    Name,
+    Color,
+    Channel,
+    Timestamp,
+    MessageId,
+    Contents,
+}
+
+impl FromStr for Placeholder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "platform" => Ok(Self::Platform),
+            "name" => Ok(Self::Name),
+            "color" => Ok(Self::Color),
+            "channel" => Ok(Self::Channel),
+            "timestamp" => Ok(Self::Timestamp),
+            "message_id" => Ok(Self::MessageId),
+            "contents" => Ok(Self::Contents),
+            other => Err(anyhow!("Unknown placeholder '{other}'")),
+        }
+    }
+}
+
+pub struct TemplateValues<'a> {
+    pub platform: &'a str,
+    pub name: Option<&'a str>,
+    pub color: Option<&'a str>,
+    pub channel: Option<&'a str>,
+    pub timestamp: &'a str,
+    pub message_id: &'a str,
+    pub contents: &'a str,
+}
+
+impl TemplateValues<'_> {
+    fn get(&self, placeholder: Placeholder) -> Option<&str> {
+        match placeholder {
+            Placeholder::Platform => Some(self.platform),
+            Placeholder::Name => self.name,
+            Placeholder::Color => self.color,
+            Placeholder::Channel => self.channel,
+            Placeholder::Timestamp => Some(self.timestamp),
+            Placeholder::MessageId => Some(self.message_id),
+            Placeholder::Contents => Some(self.contents),
+        }
+    }
+}
+
+impl Template {
+    pub fn render(&self, values: &TemplateValues) -> String {
+        let mut output = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => output.push_str(text),
+                Part::Placeholder {
+                    placeholder,
+                    fallback,
+                } => {
+                    let value = values
+                        .get(*placeholder)
+                        .filter(|value| !value.is_empty())
+                        .or(fallback.as_deref())
+                        .unwrap_or_default();
+                    output.push_str(value);
+                }
+            }
+        }
+        output
+    }
+
+    pub fn uses(&self, placeholder: Placeholder) -> bool {
+        self.parts.iter().any(|part| {
+            matches!(part, Part::Placeholder { placeholder: used, .. } if *used == placeholder)
+        })
+    }
+
+    /// Parses a template for messages that have no user attached to them
+    pub fn parse_system(s: &str) -> anyhow::Result<Self> {
+        let template = Self::from_str(s)?;
+        for placeholder in [Placeholder::Name, Placeholder::Color] {
+            if template.uses(placeholder) {
+                bail!("Placeholder {placeholder:?} cannot be used in system message templates");
+            }
+        }
+        Ok(template)
+    }
+
+    /// Parses a template describing an event, where `{name}` is the user the event is about and `{contents}` its details
+    pub fn parse_event(s: &str) -> anyhow::Result<Self> {
+        let template = Self::parse_parts(s)?;
+        if template.uses(Placeholder::Color) {
+            bail!("Placeholder Color cannot be used in event templates");
+        }
+        Ok(template)
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let template = Self::parse_parts(s)?;
+        if !template.uses(Placeholder::Contents) {
+            bail!("Template '{s}' does not include the {{contents}} placeholder");
+        }
+        Ok(template)
+    }
+}
+
+impl Template {
+    fn parse_parts(s: &str) -> anyhow::Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inner.push(c),
+                            None => bail!("Unclosed placeholder in template '{s}'"),
+                        }
+                    }
+
+                    let (name, fallback) = match inner.split_once('|') {
+                        Some((name, fallback)) => (name, Some(fallback.to_owned())),
+                        None => (inner.as_str(), None),
+                    };
+                    let placeholder = name.trim().parse()?;
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder {
+                        placeholder,
+                        fallback,
+                    });
+                }
+                '}' => bail!("Unmatched '}}' in template '{s}'"),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+pub struct MessageTemplates {
+    pub user: Template,
+    pub system: Template,
+}
+
+impl MessageTemplates {
+    pub fn parse(user: &str, system: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            user: user.parse()?,
+            system: Template::parse_system(system)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values<'a>(name: Option<&'a str>, color: Option<&'a str>) -> TemplateValues<'a> {
+        TemplateValues {
+            platform: "T",
+            name,
+            color,
+            channel: None,
+            timestamp: "12:00",
+            message_id: "42",
+            contents: "hello",
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template: Template = "[{platform}] {name}: {contents} ({message_id} at {timestamp})"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            template.render(&values(Some("bob"), None)),
+            "[T] bob: hello (42 at 12:00)"
+        );
+    }
+
+    #[test]
+    fn missing_values_use_fallback() {
+        let template: Template = "{color|ffffff} {channel} {name| x }{contents}"
+            .parse()
+            .unwrap();
+        assert_eq!(template.render(&values(None, None)), "ffffff   x hello");
+        assert_eq!(
+            template.render(&values(Some(""), Some("ff0000"))),
+            "ff0000   x hello"
+        );
+    }
+
+    #[test]
+    fn double_braces_are_literal() {
+        let template: Template = "{{{name}}} }}{{ {contents}".parse().unwrap();
+        assert_eq!(
+            template.render(&values(Some("bob"), None)),
+            "{bob} }{ hello"
+        );
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        for invalid in [
+            "{contents",
+            "{contents} }",
+            "{unknown} {contents}",
+            "{name}: no contents",
+        ] {
+            assert!(invalid.parse::<Template>().is_err(), "{invalid}");
+        }
+        assert!(Template::parse_system("{name}: {contents}").is_err());
+        assert!(Template::parse_system("{platform}: {contents}").is_ok());
+        assert!(Template::parse_event("{name} joined").is_ok());
+        assert!(Template::parse_event("{color} joined").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+//! Fixtures shared by the unit tests
+
+use crate::{message_body::Segment, user_links::Account, DbPool};
+use sqlx::{migrate::Migrator, sqlite::SqlitePoolOptions};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// An in-memory database with every migration applied
+pub async fn test_db() -> DbPool {
+    test_db_with(&MIGRATOR).await
+}
+
+/// An in-memory database with the given migrations applied, a single connection keeps it alive for the whole test
+pub async fn test_db_with(migrator: &Migrator) -> DbPool {
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    migrator.run(&db).await.unwrap();
+    db
+}
+
+pub fn text(text: &str) -> Segment {
+    Segment::Text(text.to_owned())
+}
+
+pub fn mention(user_id: Option<&str>, name: &str) -> Segment {
+    Segment::Mention {
+        user_id: user_id.map(str::to_owned),
+        name: name.to_owned(),
+    }
+}
+
+pub fn account(platform: &str, user_id: &str, user_name: Option<&str>) -> Account {
+    Account {
+        platform: platform.to_owned(),
+        user_id: user_id.to_owned(),
+        user_name: user_name.map(str::to_owned),
+    }
+}
@@ -0,0 +1,72 @@
+use crate::{DbPool, UserIdentifier};
+use anyhow::{anyhow, Context};
+use futures::TryStreamExt;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// Per-user block and allow lists, stored in the `user_filter` table
+#[derive(Clone)]
+pub struct UserFilters {
+    db: DbPool,
+    entries: Arc<RwLock<HashMap<UserIdentifier, UserFilterKind>>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserFilterKind {
+    Block,
+    Allow,
+}
+
+impl FromStr for UserFilterKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "allow" => Ok(Self::Allow),
+            other => Err(anyhow!("Unknown user filter kind '{other}'")),
+        }
+    }
+}
+
+impl UserFilters {
+    pub async fn load(db: &DbPool) -> anyhow::Result<Self> {
+        let filters = Self {
+            db: db.clone(),
+            entries: Arc::default(),
+        };
+        filters.refresh().await?;
+        Ok(filters)
+    }
+
+    /// Reloads the lists from the database, so changes made to it directly get applied
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let entries = sqlx::query!("SELECT platform, user_id, kind FROM user_filter")
+            .fetch(&self.db)
+            .map_err(anyhow::Error::from)
+            .and_then(|record| async move {
+                let user = UserIdentifier {
+                    platform: record.platform,
+                    user_id: record.user_id,
+                };
+                Ok((user, record.kind.parse()?))
+            })
+            .try_collect()
+            .await
+            .context("Could not load user filters")?;
+
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+
+    pub fn get(&self, user: &UserIdentifier) -> Option<UserFilterKind> {
+        self.entries.read().unwrap().get(user).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+}
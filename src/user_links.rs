@@ -0,0 +1,561 @@
+use crate::{DbPool, UserIdentifier};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a code from `!link` can be used
+pub const LINK_CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// People with accounts on several platforms, stored in the `identity` and `identity_account` tables
+#[derive(Clone)]
+pub struct UserLinks {
+    db: DbPool,
+    entries: Arc<RwLock<IdentityEntries>>,
+    /// Code -> link requested with `!link`, waiting for the code to be posted on the other platform
+    pending: Arc<Mutex<HashMap<String, PendingLink>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingLink {
+    pub account: Account,
+    pub target_platform: String,
+    /// Only this user can post the code on the target platform
+    pub target_user_name: String,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct IdentityEntries {
+    identity_ids: HashMap<UserIdentifier, i64>,
+    identities: HashMap<i64, Identity>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkedUser {
+    pub user_id: String,
+    /// Name used to mention the user, mentions are not translated without one
+    pub user_name: Option<String>,
+}
+
+/// A row of the `identity_account` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub platform: String,
+    pub user_id: String,
+    pub user_name: Option<String>,
+}
+
+/// One person, with at most one account per platform
+#[derive(Debug, Clone, Serialize)]
+pub struct Identity {
+    pub id: i64,
+    /// Shown instead of the account's own name when set
+    pub display_name: Option<String>,
+    pub accounts: Vec<Account>,
+}
+
+impl UserLinks {
+    pub async fn load(db: &DbPool) -> anyhow::Result<Self> {
+        let user_links = Self {
+            db: db.clone(),
+            entries: Arc::default(),
+            pending: Arc::default(),
+        };
+        user_links.refresh().await?;
+        Ok(user_links)
+    }
+
+    /// Reloads the identities from the database
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let identities = self.list().await?;
+
+        let mut entries = IdentityEntries::default();
+        for identity in identities {
+            for account in &identity.accounts {
+                let user = UserIdentifier {
+                    platform: account.platform.clone(),
+                    user_id: account.user_id.clone(),
+                };
+                entries.identity_ids.insert(user, identity.id);
+            }
+            entries.identities.insert(identity.id, identity);
+        }
+
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<Identity>> {
+        let mut identities: Vec<Identity> =
+            sqlx::query!("SELECT id, display_name FROM identity ORDER BY id")
+                .fetch_all(&self.db)
+                .await
+                .context("Could not load identities")?
+                .into_iter()
+                .map(|record| Identity {
+                    id: record.id,
+                    display_name: record.display_name,
+                    accounts: Vec::new(),
+                })
+                .collect();
+
+        let accounts = sqlx::query!(
+            "SELECT identity_id, platform, user_id, user_name FROM identity_account
+            ORDER BY platform"
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Could not load identity accounts")?;
+
+        for record in accounts {
+            let Ok(index) =
+                identities.binary_search_by_key(&record.identity_id, |identity| identity.id)
+            else {
+                continue;
+            };
+            identities[index].accounts.push(Account {
+                platform: record.platform,
+                user_id: record.user_id,
+                user_name: record.user_name,
+            });
+        }
+        Ok(identities)
+    }
+
+    /// Makes both accounts part of the same identity, returning its id.
+    ///
+    /// The other account replaces the identity's current account on its platform,
+    /// and the rest of the other account's identity is merged into it.
+    pub async fn link(&self, account: &Account, other: &Account) -> anyhow::Result<i64> {
+        if account.platform == other.platform {
+            bail!("Linked accounts have to be on different platforms");
+        }
+
+        let mut tx = self.db.begin().await?;
+
+        let identity_id = match identity_of(&mut tx, account).await? {
+            Some(identity_id) => identity_id,
+            None => sqlx::query_scalar!("INSERT INTO identity DEFAULT VALUES RETURNING id")
+                .fetch_one(&mut *tx)
+                .await
+                .context("Could not create identity")?,
+        };
+        upsert_account(&mut tx, account, identity_id).await?;
+
+        let other_identity_id = identity_of(&mut tx, other).await?;
+        if other_identity_id != Some(identity_id) {
+            sqlx::query!(
+                "DELETE FROM identity_account WHERE identity_id = ? AND platform = ?",
+                identity_id,
+                other.platform,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if let Some(other_identity_id) = other_identity_id.filter(|id| *id != identity_id) {
+            sqlx::query!(
+                "UPDATE identity
+                SET display_name = COALESCE(display_name, (SELECT display_name FROM identity WHERE id = ?2))
+                WHERE id = ?1",
+                identity_id,
+                other_identity_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            // Accounts on platforms the identity already has an account on are dropped
+            sqlx::query!(
+                "DELETE FROM identity_account
+                WHERE identity_id = ?2
+                    AND platform IN (SELECT platform FROM identity_account WHERE identity_id = ?1)",
+                identity_id,
+                other_identity_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE identity_account SET identity_id = ?1 WHERE identity_id = ?2",
+                identity_id,
+                other_identity_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!("DELETE FROM identity WHERE id = ?", other_identity_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        upsert_account(&mut tx, other, identity_id).await?;
+
+        tx.commit().await.context("Could not store user link")?;
+        self.refresh().await?;
+        Ok(identity_id)
+    }
+
+    /// Removes the account from its identity, returns whether it had one
+    pub async fn unlink(&self, user: &UserIdentifier) -> anyhow::Result<bool> {
+        let removed = sqlx::query!(
+            "DELETE FROM identity_account WHERE platform = ? AND user_id = ?",
+            user.platform,
+            user.user_id,
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not remove user link")?
+        .rows_affected();
+
+        sqlx::query!(
+            "DELETE FROM identity WHERE id NOT IN (SELECT identity_id FROM identity_account)"
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not remove empty identities")?;
+
+        self.refresh().await?;
+        Ok(removed > 0)
+    }
+
+    /// Returns whether the identity exists
+    pub async fn set_display_name(
+        &self,
+        identity_id: i64,
+        display_name: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let updated = sqlx::query!(
+            "UPDATE identity SET display_name = ? WHERE id = ?",
+            display_name,
+            identity_id,
+        )
+        .execute(&self.db)
+        .await
+        .context("Could not update identity")?
+        .rows_affected();
+
+        self.refresh().await?;
+        Ok(updated > 0)
+    }
+
+    /// Creates a one-time code that links the account to the target account once it is posted from there
+    pub fn request_link(
+        &self,
+        account: Account,
+        target_platform: String,
+        target_user_name: &str,
+    ) -> String {
+        let mut pending = self.pending.lock().unwrap();
+        let now = Instant::now();
+        pending.retain(|_, link| link.expires_at > now);
+
+        let code = loop {
+            let code = random_code();
+            if !pending.contains_key(&code) {
+                break code;
+            }
+        };
+        pending.insert(
+            code.clone(),
+            PendingLink {
+                account,
+                target_platform,
+                target_user_name: target_user_name.trim_start_matches('@').to_lowercase(),
+                expires_at: now + LINK_CODE_LIFETIME,
+            },
+        );
+        code
+    }
+
+    /// Returns the pending link if the code was requested for the given account, the code can only be used once
+    pub fn redeem_code(&self, code: &str, platform: &str, user_name: &str) -> Option<PendingLink> {
+        let mut pending = self.pending.lock().unwrap();
+        let link = pending.get(code)?;
+        if link.expires_at <= Instant::now() {
+            pending.remove(code);
+            return None;
+        }
+        if link.target_platform != platform || link.target_user_name != user_name.to_lowercase() {
+            return None;
+        }
+        pending.remove(code)
+    }
+
+    /// The account of the same person on the target platform
+    pub fn get(&self, user: &UserIdentifier, target_platform: &str) -> Option<LinkedUser> {
+        let entries = self.entries.read().unwrap();
+        let identity_id = entries.identity_ids.get(user)?;
+        entries.identities[identity_id]
+            .accounts
+            .iter()
+            .find(|account| {
+                account.platform == target_platform && account.platform != user.platform
+            })
+            .map(|account| LinkedUser {
+                user_id: account.user_id.clone(),
+                user_name: account.user_name.clone(),
+            })
+    }
+
+    /// The name set for the user's identity, used instead of their name on the platform
+    pub fn display_name(&self, user: &UserIdentifier) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        let identity_id = entries.identity_ids.get(user)?;
+        entries.identities[identity_id].display_name.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().identities.len()
+    }
+}
+
+async fn identity_of(
+    conn: &mut SqliteConnection,
+    account: &Account,
+) -> anyhow::Result<Option<i64>> {
+    sqlx::query_scalar!(
+        "SELECT identity_id FROM identity_account WHERE platform = ? AND user_id = ?",
+        account.platform,
+        account.user_id,
+    )
+    .fetch_optional(conn)
+    .await
+    .context("Could not look up identity")
+}
+
+async fn upsert_account(
+    conn: &mut SqliteConnection,
+    account: &Account,
+    identity_id: i64,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "INSERT INTO identity_account (platform, user_id, user_name, identity_id)
+        VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(platform, user_id) DO UPDATE
+        SET user_name = COALESCE(?3, user_name), identity_id = ?4",
+        account.platform,
+        account.user_id,
+        account.user_name,
+        identity_id,
+    )
+    .execute(conn)
+    .await
+    .context("Could not store identity account")?;
+    Ok(())
+}
+
+/// Six digits, only used to confirm that the account on the other platform belongs to the same person
+fn random_code() -> String {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    format!("{:06}", hasher.finish() % 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{account, test_db, test_db_with, MIGRATOR};
+    use sqlx::migrate::Migrator;
+    use std::borrow::Cow;
+
+    fn user(platform: &str, user_id: &str) -> UserIdentifier {
+        UserIdentifier {
+            platform: platform.to_owned(),
+            user_id: user_id.to_owned(),
+        }
+    }
+
+    fn account_ids(identity: &Identity) -> Vec<(&str, &str)> {
+        identity
+            .accounts
+            .iter()
+            .map(|account| (account.platform.as_str(), account.user_id.as_str()))
+            .collect()
+    }
+
+    async fn user_links() -> UserLinks {
+        UserLinks::load(&test_db().await).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn link_creates_identity() {
+        let links = user_links().await;
+        let id = links
+            .link(
+                &account("twitch", "1", None),
+                &account("factorio", "alice", Some("alice")),
+            )
+            .await
+            .unwrap();
+
+        let identities = links.list().await.unwrap();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].id, id);
+        assert_eq!(
+            account_ids(&identities[0]),
+            [("factorio", "alice"), ("twitch", "1")]
+        );
+        let linked = links.get(&user("twitch", "1"), "factorio").unwrap();
+        assert_eq!(linked.user_id, "alice");
+        assert_eq!(linked.user_name.as_deref(), Some("alice"));
+        assert!(links.get(&user("twitch", "1"), "twitch").is_none());
+    }
+
+    #[tokio::test]
+    async fn link_replaces_account_on_same_platform() {
+        let links = user_links().await;
+        let id = links
+            .link(
+                &account("twitch", "1", None),
+                &account("factorio", "alice", None),
+            )
+            .await
+            .unwrap();
+        let relinked_id = links
+            .link(
+                &account("twitch", "1", None),
+                &account("factorio", "alice2", None),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(relinked_id, id);
+        let identities = links.list().await.unwrap();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(
+            account_ids(&identities[0]),
+            [("factorio", "alice2"), ("twitch", "1")]
+        );
+        assert!(links.get(&user("factorio", "alice"), "twitch").is_none());
+    }
+
+    #[tokio::test]
+    async fn link_merges_identities() {
+        let links = user_links().await;
+        let id = links
+            .link(
+                &account("twitch", "1", None),
+                &account("factorio", "alice", None),
+            )
+            .await
+            .unwrap();
+        let other_id = links
+            .link(
+                &account("discord", "9", None),
+                &account("factorio", "bob", None),
+            )
+            .await
+            .unwrap();
+        links.set_display_name(other_id, Some("Bob")).await.unwrap();
+
+        // Bob's factorio account is dropped, alice already is the identity's factorio account
+        let merged_id = links
+            .link(
+                &account("twitch", "1", None),
+                &account("discord", "9", None),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(merged_id, id);
+        let identities = links.list().await.unwrap();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].display_name.as_deref(), Some("Bob"));
+        assert_eq!(
+            account_ids(&identities[0]),
+            [("discord", "9"), ("factorio", "alice"), ("twitch", "1")]
+        );
+        assert_eq!(
+            links.display_name(&user("discord", "9")).as_deref(),
+            Some("Bob")
+        );
+    }
+
+    #[tokio::test]
+    async fn link_rejects_same_platform() {
+        let links = user_links().await;
+        assert!(links
+            .link(&account("twitch", "1", None), &account("twitch", "2", None))
+            .await
+            .is_err());
+        assert_eq!(links.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn unlink_removes_empty_identities() {
+        let links = user_links().await;
+        links
+            .link(
+                &account("twitch", "1", None),
+                &account("factorio", "alice", None),
+            )
+            .await
+            .unwrap();
+
+        assert!(links.unlink(&user("twitch", "1")).await.unwrap());
+        assert!(!links.unlink(&user("twitch", "1")).await.unwrap());
+        assert_eq!(links.len(), 1);
+
+        assert!(links.unlink(&user("factorio", "alice")).await.unwrap());
+        assert_eq!(links.len(), 0);
+        assert!(links.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn migration_joins_chained_user_links() {
+        // user_link as it was before the user names were added
+        const USER_LINK_NAMES_VERSION: i64 = 20240506143000;
+        let before_user_link_names = Migrator {
+            migrations: Cow::Owned(
+                MIGRATOR
+                    .iter()
+                    .filter(|migration| migration.version < USER_LINK_NAMES_VERSION)
+                    .cloned()
+                    .collect(),
+            ),
+            ..Migrator::DEFAULT
+        };
+        let db = test_db_with(&before_user_link_names).await;
+
+        for (source_platform, source_user_id, target_platform, target_user_id) in [
+            ("twitch", "1", "factorio", "alice"),
+            ("factorio", "alice", "discord", "9"),
+            ("twitch", "2", "factorio", "bob"),
+            // A second factorio account for the same person is dropped
+            ("factorio", "bob2", "twitch", "2"),
+        ] {
+            sqlx::query(
+                "INSERT INTO user_link (source_platform, source_user_id, target_platform, target_user_id)
+                VALUES (?, ?, ?, ?)",
+            )
+            .bind(source_platform)
+            .bind(source_user_id)
+            .bind(target_platform)
+            .bind(target_user_id)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        MIGRATOR.run(&db).await.unwrap();
+
+        let links = UserLinks::load(&db).await.unwrap();
+        let identities = links.list().await.unwrap();
+        assert_eq!(identities.len(), 2);
+        assert_eq!(
+            account_ids(&identities[0]),
+            [("discord", "9"), ("factorio", "alice"), ("twitch", "1")]
+        );
+        assert_eq!(
+            account_ids(&identities[1]),
+            [("factorio", "bob"), ("twitch", "2")]
+        );
+        // Factorio players are named after their id
+        let linked = links.get(&user("twitch", "1"), "factorio").unwrap();
+        assert_eq!(linked.user_name.as_deref(), Some("alice"));
+    }
+}